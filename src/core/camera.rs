@@ -2,6 +2,7 @@
 
 //====================================================================
 
+#[derive(Clone, Copy)]
 pub struct Camera {
     position: glam::Vec3,
     yaw: f32,   //Left and right
@@ -17,6 +18,27 @@ impl Camera {
         }
     }
 
+    pub fn get_position(&self) -> glam::Vec3 {
+        return self.position;
+    }
+
+    //Blends between two camera states, used to draw between simulation ticks
+    pub fn lerp(&self, other: &Camera, t: f32) -> Camera {
+        Camera {
+            position: self.position.lerp(other.position, t),
+            yaw: self.yaw + (other.yaw - self.yaw) * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+        }
+    }
+
+    //Direction the camera is looking in, matching the view built in build_matrix
+    pub fn get_direction(&self) -> glam::Vec3 {
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+
+        return -glam::Vec3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize();
+    }
+
     pub fn build_matrix(&self) -> glam::Mat4 {
         let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
@@ -113,71 +135,155 @@ impl CameraUniform {
     pub fn _update_view_proj(&mut self, camera: &Camera, projection: &Projection) {
         self.view_proj = (projection.build_matrix() * camera.build_matrix()).to_cols_array_2d();
     }
+
+    pub fn get_view_proj(&self) -> glam::Mat4 {
+        return glam::Mat4::from_cols_array_2d(&self.view_proj);
+    }
+}
+
+//====================================================================
+
+//The six planes bounding what the camera can see, with their normals pointing inwards.
+//Each plane is stored as (normal, distance) so a point is inside it when
+//normal.dot(point) + distance >= 0.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [glam::Vec4; 6],
+}
+
+impl Frustum {
+    //Pulls the planes out of the rows of a view projection matrix. The projection uses
+    //OpenGL depth (-1 to 1), so the near plane is row 3 + row 2 like the other sides.
+    pub fn from_view_proj(view_proj: glam::Mat4) -> Self {
+        let row_0 = view_proj.row(0);
+        let row_1 = view_proj.row(1);
+        let row_2 = view_proj.row(2);
+        let row_3 = view_proj.row(3);
+
+        let planes = [
+            row_3 + row_0, //Left
+            row_3 - row_0, //Right
+            row_3 + row_1, //Bottom
+            row_3 - row_1, //Top
+            row_3 + row_2, //Near
+            row_3 - row_2, //Far
+        ];
+
+        Self {
+            planes: planes.map(|plane| plane / plane.truncate().length()),
+        }
+    }
+
+    //Checks the corner of the box furthest along each plane's normal. If even that corner
+    //is behind a plane the whole box is outside. Boxes near the corners of the frustum
+    //can pass without being visible, which only costs a wasted draw.
+    pub fn intersects_box(&self, min: glam::Vec3, max: glam::Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            let furthest = glam::Vec3::select(normal.cmpge(glam::Vec3::ZERO), max, min);
+
+            normal.dot(furthest) + plane.w >= 0.
+        })
+    }
 }
 
 //====================================================================
 
 pub struct CameraController {
+    //Degrees to turn this update, from the arrow keys and the mouse
     look_dir: glam::Vec2,
-    move_dir: glam::Vec3,
+    //Current movement in units per second, relative to the camera's facing
+    velocity: glam::Vec3,
 
+    //Degrees per second
     look_speed: f32,
+    //Units per second
     move_speed: f32,
+
+    //Degrees turned per pixel of mouse movement
+    pub mouse_sensitivity: f32,
+    pub invert_y: bool,
+
+    //How quickly the velocity catches up to the keys being held, per second.
+    //None starts and stops instantly.
+    pub acceleration: Option<f32>,
 }
 
 impl CameraController {
     pub fn new(look_speed: f32, move_speed: f32) -> Self {
         Self {
             look_dir: glam::Vec2::ZERO,
-            move_dir: glam::Vec3::ZERO,
+            velocity: glam::Vec3::ZERO,
             look_speed,
             move_speed,
+
+            mouse_sensitivity: 0.1,
+            invert_y: false,
+
+            acceleration: Some(12.),
         }
     }
 
-    pub fn update(&mut self, camera: &mut Camera, inputs: &crate::core::state::InputController) {
+    pub fn process_mouse(&mut self, delta_x: f64, delta_y: f64) {
+        let delta_y = if self.invert_y { -delta_y } else { delta_y };
+
+        self.look_dir.x += delta_x as f32 * self.mouse_sensitivity;
+        self.look_dir.y += delta_y as f32 * self.mouse_sensitivity;
+    }
+
+    pub fn update(&mut self, camera: &mut Camera, inputs: &crate::core::state::InputController, dt: std::time::Duration) {
 
         use winit::event::*;
 
-        if inputs.key_pressed(&VirtualKeyCode::W) { self.move_dir.z -= self.move_speed }    //Forward
-        if inputs.key_pressed(&VirtualKeyCode::S) { self.move_dir.z += self.move_speed }    //Backward
-        if inputs.key_pressed(&VirtualKeyCode::A) { self.move_dir.x += self.move_speed }    //Left
-        if inputs.key_pressed(&VirtualKeyCode::D) { self.move_dir.x -= self.move_speed }    //Right
+        let dt = dt.as_secs_f32();
+        let mut move_input = glam::Vec3::ZERO;
+
+        if inputs.key_pressed(&VirtualKeyCode::W) { move_input.z -= 1. }    //Forward
+        if inputs.key_pressed(&VirtualKeyCode::S) { move_input.z += 1. }    //Backward
+        if inputs.key_pressed(&VirtualKeyCode::A) { move_input.x += 1. }    //Left
+        if inputs.key_pressed(&VirtualKeyCode::D) { move_input.x -= 1. }    //Right
+
+        if inputs.key_pressed(&VirtualKeyCode::Space)   { move_input.y += 1. }  //Up
+        if inputs.key_pressed(&VirtualKeyCode::LShift)  { move_input.y -= 1. }  //Down
 
-        if inputs.key_pressed(&VirtualKeyCode::Space)   { self.move_dir.y += self.move_speed }  //Up
-        if inputs.key_pressed(&VirtualKeyCode::LShift)  { self.move_dir.y -= self.move_speed }  //Down
+        if inputs.key_pressed(&VirtualKeyCode::Up)      { self.look_dir.y -= self.look_speed * dt }  //Look up
+        if inputs.key_pressed(&VirtualKeyCode::Down)    { self.look_dir.y += self.look_speed * dt }  //Look down
+        if inputs.key_pressed(&VirtualKeyCode::Left)    { self.look_dir.x -= self.look_speed * dt }  //Look left
+        if inputs.key_pressed(&VirtualKeyCode::Right)   { self.look_dir.x += self.look_speed * dt }  //Look right
 
-        if inputs.key_pressed(&VirtualKeyCode::Up)      { self.look_dir.y -= self.look_speed }  //Look up
-        if inputs.key_pressed(&VirtualKeyCode::Down)    { self.look_dir.y += self.look_speed }  //Look down
-        if inputs.key_pressed(&VirtualKeyCode::Left)    { self.look_dir.x -= self.look_speed }  //Look left
-        if inputs.key_pressed(&VirtualKeyCode::Right)   { self.look_dir.x += self.look_speed }  //Look right
+        let target_velocity = move_input * self.move_speed;
 
-        //let dt = dt.as_secs_f32();
+        match self.acceleration {
+            Some(acceleration) => {
+                //Framerate independent ease towards the target velocity
+                let blend = 1. - (-acceleration * dt).exp();
+                self.velocity += (target_velocity - self.velocity) * blend;
+            }
+            None => self.velocity = target_velocity,
+        }
+
+        let move_dir = self.velocity * dt;
 
         // Move forward/backward and left/right
         let (yaw_sin, yaw_cos) = camera.yaw.sin_cos();
         let forward = glam::Vec3::new(yaw_cos, 0.0, yaw_sin).normalize();
         let right = glam::Vec3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        camera.position += forward * self.move_dir.z;
-        camera.position += right * self.move_dir.x;
+        camera.position += forward * move_dir.z;
+        camera.position += right * move_dir.x;
 
         // Move up/down. Since we don't use roll, we can just
         // modify the y coordinate directly.
-        camera.position.y += self.move_dir.y;
+        camera.position.y += move_dir.y;
 
         // Rotate
-        //camera.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
-        //camera.pitch += Rad(-self.rotate_vertical) * self.sensitivity * dt;
-
         camera.yaw += self.look_dir.x.to_radians();
         camera.pitch += self.look_dir.y.to_radians();
 
-        /*if self.move_dir != glam::Vec3::ZERO {
+        /*if move_dir != glam::Vec3::ZERO {
             println!("Camera pos = {}", camera.position);
         }*/
 
         self.look_dir = glam::Vec2::ZERO;
-        self.move_dir = glam::Vec3::ZERO;
         
         let high = -65f32.to_radians();
         let low = 65f32.to_radians();
@@ -192,4 +298,65 @@ impl CameraController {
     }
 }
 
-//====================================================================
\ No newline at end of file
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Camera at the origin looking down -x, seeing 0.1 to 100 units away
+    fn test_frustum() -> (Frustum, glam::Vec3) {
+        let camera = Camera::new(glam::Vec3::ZERO, 0., 0.);
+        let projection = Projection::new(800, 600, 60f32.to_radians(), 0.1, 100.);
+        let view_proj = CameraUniform::from_camera(&camera, &projection).get_view_proj();
+
+        (Frustum::from_view_proj(view_proj), camera.get_direction())
+    }
+
+    fn intersects(frustum: &Frustum, centre: glam::Vec3) -> bool {
+        frustum.intersects_box(centre - glam::Vec3::ONE, centre + glam::Vec3::ONE)
+    }
+
+    #[test]
+    fn frustum_keeps_boxes_in_front() {
+        let (frustum, forward) = test_frustum();
+
+        assert!(intersects(&frustum, forward * 10.));
+        assert!(intersects(&frustum, forward * 98.));
+        //Around the camera, crossing the near plane
+        assert!(intersects(&frustum, glam::Vec3::ZERO));
+    }
+
+    #[test]
+    fn frustum_culls_boxes_behind_and_past_far() {
+        let (frustum, forward) = test_frustum();
+
+        assert!(!intersects(&frustum, forward * -10.));
+        assert!(!intersects(&frustum, forward * 150.));
+    }
+
+    #[test]
+    fn frustum_keeps_boxes_straddling_sides() {
+        let (frustum, forward) = test_frustum();
+
+        //Half the horizontal view is about 37.6 degrees, so the sides are 7.7 units out at
+        //a distance of 10 (on z since the camera looks down x)
+        let half_width = (30f32.to_radians().tan() * 800. / 600.) * 10.;
+        assert!((half_width - 7.7).abs() < 0.01);
+
+        for side in [-1., 1.] {
+            let edge = forward * 10. + glam::Vec3::Z * half_width * side;
+
+            assert!(intersects(&frustum, edge));
+            assert!(!intersects(&frustum, edge + glam::Vec3::Z * 2. * side));
+        }
+
+        //Top and bottom are 5.8 units out
+        for side in [-1., 1.] {
+            let edge = forward * 10. + glam::Vec3::Y * 5.77 * side;
+
+            assert!(intersects(&frustum, edge));
+            assert!(!intersects(&frustum, edge + glam::Vec3::Y * 2. * side));
+        }
+    }
+}
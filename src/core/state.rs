@@ -4,9 +4,25 @@ use wgpu::util::DeviceExt;
 
 use crate::{
     core::camera,
-    render::texture,
-    voxels::{chunk, model},
+    render::{light, palette, texture},
+    voxels::{chunk, mesh_arena, model, raycast, registry, terrain, voxel},
 };
+
+//====================================================================
+
+pub const WORLD_SEED: u64 = 8_675_309;
+pub const SAVE_DIR: &str = "saves/world";
+pub const VOXEL_CONFIG: &str = "res/voxels.toml";
+
+//How far away voxels can be placed or removed, in world units
+pub const VOXEL_REACH: f32 = 5.;
+
+//Simulation runs at a fixed rate no matter how fast frames are drawn
+pub const TICK_RATE: u64 = 60;
+pub const TICK_DURATION: std::time::Duration = std::time::Duration::from_nanos(1_000_000_000 / TICK_RATE);
+//Longest frame the simulation will try to catch up on, so a stall doesn't snowball
+pub const MAX_FRAME_DURATION: std::time::Duration = std::time::Duration::from_millis(250);
+
 //====================================================================
 
 #[derive(Default)]
@@ -27,12 +43,7 @@ impl InputController {
     }
 
     fn check_key(&self, key: &winit::event::VirtualKeyCode) -> Option<usize> {
-        for x in 0..self.pressed.len() {
-            if key == &self.pressed[x] {
-                return Some(x);
-            }
-        }
-        return None;
+        return self.pressed.iter().position(|pressed| pressed == key);
     }
 
     pub fn key_pressed(&self, key: &winit::event::VirtualKeyCode) -> bool {
@@ -55,22 +66,46 @@ pub struct State {
     size: winit::dpi::PhysicalSize<u32>,
 
     camera: camera::Camera,
+    //Camera as it was at the start of the last tick, for interpolation
+    previous_camera: camera::Camera,
     camera_projection: camera::Projection,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
     camera_controller: camera::CameraController,
+    //Frustum and position of the camera last sent to the GPU, chunks outside of it
+    //aren't drawn and translucent chunks are sorted by distance from it
+    camera_frustum: camera::Frustum,
+    camera_position: glam::Vec3,
+
+    light: light::Light,
+    light_buffer: wgpu::Buffer,
+    light_bind_group: wgpu::BindGroup,
+
+    //Colour of each voxel type
+    palette_bind_group: wgpu::BindGroup,
 
     input_controller: InputController,
+    cursor_grabbed: bool,
+
+    tick_accumulator: std::time::Duration,
 
     depth_texture: texture::Texture,
 
     chunks: chunk::ChunkCollection,
+    //Holds the meshes of every loaded chunk
+    chunk_meshes: mesh_arena::ChunkMeshArena,
+    selected_voxel: Option<voxel::VoxelId>,
+    //Chunks drawn and culled in the last frame
+    chunk_draw_stats: chunk::ChunkDrawStats,
+    translucent_draw_stats: chunk::ChunkDrawStats,
 
     render_pipeline: wgpu::RenderPipeline,
+    //Blends translucent voxels over the opaque ones without writing depth
+    translucent_pipeline: wgpu::RenderPipeline,
 }
 
 impl State {
-    pub async fn new(window: &winit::window::Window) -> Self {
+    pub async fn new(window: &winit::window::Window, chunk_size: chunk::ChunkSize) -> Self {
         //--------------------------------------------------
 
         let size = window.inner_size();
@@ -93,7 +128,8 @@ impl State {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: Some("Wgpu Device"),
-                    features: wgpu::Features::empty(),
+                    //Multi-draw is used for the chunks where the adapter has it
+                    features: adapter.features() & mesh_arena::MULTI_DRAW_FEATURES,
                     limits: wgpu::Limits::default(),
                 },
                 None,
@@ -115,21 +151,14 @@ impl State {
 
         //--------------------------------------------------
 
-        let camera = camera::Camera::new(glam::Vec3::new(0., 1., 2.), 90f32.to_radians(), 0.);
+        let camera = camera::Camera::new(glam::Vec3::new(0., 8., 2.), 90f32.to_radians(), 0.);
         let camera_projection =
             camera::Projection::new(size.width, size.height, 45f32.to_radians(), 0.1, 100.);
 
-        let camera_controller = camera::CameraController::new(1., 0.02);
+        let camera_controller = camera::CameraController::new(60., 3.);
 
         let camera_uniform = camera::CameraUniform::from_camera(&camera, &camera_projection);
 
-        let new_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("My New Buffer"),
-            size: 12,
-            usage: wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera Buffer"),
             contents: bytemuck::cast_slice(&[camera_uniform]),
@@ -162,10 +191,92 @@ impl State {
 
         //--------------------------------------------------
 
-        let mut chunks = chunk::ChunkCollection::new();
+        let light = light::Light::new(
+            glam::Vec3::new(-0.4, -1., -0.6),
+            glam::Vec3::new(0.75, 0.72, 0.65),
+            0.35,
+        );
+
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[light::LightUniform::from_light(&light)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let light_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Light Bind Group Layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light Bind Group"),
+            layout: &light_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            }],
+        });
+
+        //--------------------------------------------------
+
+        //Chunks around the camera are generated on worker threads once updates start
+        let registry = match registry::VoxelRegistry::load_from_file(std::path::Path::new(VOXEL_CONFIG)) {
+            Ok(registry) => registry,
+            Err(e) => {
+                eprintln!("Failed to load voxel types from {}, using the defaults: {}", VOXEL_CONFIG, e);
+                registry::VoxelRegistry::default()
+            }
+        };
+
+        let selected_voxel = registry.get_id("stone").or_else(|| registry.iter().next().map(|voxel| voxel.id));
 
-        //chunks.spawn_chunk(&device, glam::IVec3::new(0, 0, 0));
-        chunks.spawn_chunks_in_range(&device, glam::IVec3::new(0, 0, 0));
+        let palette_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Palette Buffer"),
+            contents: bytemuck::cast_slice(&[palette::PaletteUniform::from_registry(&registry, WORLD_SEED)]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let palette_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Palette Bind Group Layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let palette_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Palette Bind Group"),
+            layout: &palette_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: palette_buffer.as_entire_binding(),
+            }],
+        });
+
+        let chunks = chunk::ChunkCollection::new(
+            chunk_size,
+            chunk::MeshingMode::Greedy,
+            Box::new(terrain::HeightmapGenerator::new(WORLD_SEED, &registry)),
+            std::sync::Arc::new(registry),
+        );
+        let chunk_meshes = mesh_arena::ChunkMeshArena::new(&device);
 
         //--------------------------------------------------
 
@@ -177,7 +288,11 @@ impl State {
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout],
+                bind_group_layouts: &[
+                    &camera_bind_group_layout,
+                    &light_bind_group_layout,
+                    &palette_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
 
@@ -207,50 +322,57 @@ impl State {
             source: wgpu::ShaderSource::Wgsl(include_str!("../render/shader.wgsl").into()),
         });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[
-                    model::Vertex::desc(),
-                    //voxel::VOXEL_DESC,
-                    //voxel::VoxelInstance::desc(),
-                ],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        let create_pipeline = |label: &str, blend: wgpu::BlendState, depth_write_enabled: bool| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[
+                        model::Vertex::desc(),
+                        mesh_arena::ChunkInstance::desc(),
+                        //voxel::VOXEL_DESC,
+                        //voxel::VoxelInstance::desc(),
+                    ],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: config.format,
+                        blend: Some(blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: texture::Texture::DEPTH_FORMAT,
+                    depth_write_enabled,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+
+        let render_pipeline = create_pipeline("Render Pipeline", wgpu::BlendState::REPLACE, true);
+        let translucent_pipeline =
+            create_pipeline("Translucent Render Pipeline", wgpu::BlendState::ALPHA_BLENDING, false);
 
         //--------------------------------------------------
 
@@ -262,18 +384,35 @@ impl State {
             size,
 
             camera,
+            previous_camera: camera,
             camera_projection,
             camera_buffer,
             camera_bind_group,
             camera_controller,
+            camera_frustum: camera::Frustum::from_view_proj(camera_uniform.get_view_proj()),
+            camera_position: camera.get_position(),
+
+            light,
+            light_buffer,
+            light_bind_group,
+
+            palette_bind_group,
 
             input_controller: InputController::default(),
+            cursor_grabbed: false,
+
+            tick_accumulator: std::time::Duration::ZERO,
 
             depth_texture,
 
             chunks,
+            chunk_meshes,
+            selected_voxel,
+            chunk_draw_stats: chunk::ChunkDrawStats::default(),
+            translucent_draw_stats: chunk::ChunkDrawStats::default(),
 
             render_pipeline,
+            translucent_pipeline,
         }
 
         //--------------------------------------------------
@@ -305,9 +444,24 @@ impl State {
                 ..
             } => {
                 if *state == winit::event::ElementState::Pressed {
-                    self.input_controller.add_key(&keycode);
+                    if !self.input_controller.key_pressed(keycode) {
+                        self.key_just_pressed(*keycode);
+                    }
+                    self.input_controller.add_key(keycode);
                 } else {
-                    self.input_controller.remove_key(&keycode);
+                    self.input_controller.remove_key(keycode);
+                }
+                return true;
+            }
+            winit::event::WindowEvent::MouseInput {
+                state: winit::event::ElementState::Pressed,
+                button,
+                ..
+            } => {
+                match button {
+                    winit::event::MouseButton::Left => self.remove_targeted_voxel(),
+                    winit::event::MouseButton::Right => self.place_selected_voxel(),
+                    _ => return false,
                 }
                 return true;
             }
@@ -316,12 +470,160 @@ impl State {
         }
     }
 
-    pub fn update(&mut self) {
-        self.camera_controller
-            .update(&mut self.camera, &self.input_controller);
+    //Rewrites the whole light uniform, so direction, colour and ambient can all change
+    pub fn set_light(&mut self, light: light::Light) {
+        self.light = light;
+        self.queue.write_buffer(
+            &self.light_buffer,
+            0,
+            bytemuck::cast_slice(&[light::LightUniform::from_light(&self.light)]),
+        );
+    }
+
+    pub fn mouse_motion(&mut self, delta: (f64, f64)) {
+        //Mouse look only while the cursor is grabbed so it can still be used normally
+        if self.cursor_grabbed {
+            self.camera_controller.process_mouse(delta.0, delta.1);
+        }
+    }
+
+    pub fn set_cursor_grab(&mut self, window: &winit::window::Window, grabbed: bool) {
+        if let Err(e) = window.set_cursor_grab(grabbed) {
+            eprintln!("Unable to change cursor grab: {}", e);
+            return;
+        }
+        window.set_cursor_visible(!grabbed);
+
+        self.cursor_grabbed = grabbed;
+    }
+
+    pub fn toggle_cursor_grab(&mut self, window: &winit::window::Window) {
+        self.set_cursor_grab(window, !self.cursor_grabbed);
+    }
+
+    fn remove_targeted_voxel(&mut self) {
+        if let Some(hit) = raycast::raycast_from_camera(&self.chunks, &self.camera, VOXEL_REACH) {
+            self.chunks.set_voxel(hit.voxel_pos, None);
+        }
+    }
+
+    //Places the selected voxel against the face of the targeted voxel
+    fn place_selected_voxel(&mut self) {
+        if let Some(hit) = raycast::raycast_from_camera(&self.chunks, &self.camera, VOXEL_REACH) {
+            let place_pos = hit.voxel_pos + hit.side.get_normal();
+
+            //Don't bury the camera
+            if place_pos == chunk::ChunkCollection::position_to_world(self.camera.get_position()) {
+                return;
+            }
+
+            self.chunks.set_voxel(place_pos, self.selected_voxel);
+        }
+    }
+
+    //Selects the nth voxel type in the registry, in order of id
+    fn select_voxel(&mut self, index: usize) {
+        if let Some(voxel) = self.chunks.get_registry().iter().nth(index) {
+            println!("Selected voxel: {}", voxel.name);
+            self.selected_voxel = Some(voxel.id);
+        }
+    }
+
+    fn key_just_pressed(&mut self, key: winit::event::VirtualKeyCode) {
+        match key {
+            winit::event::VirtualKeyCode::M => {
+                let meshing_mode = match self.chunks.get_meshing_mode() {
+                    chunk::MeshingMode::Naive => chunk::MeshingMode::Greedy,
+                    chunk::MeshingMode::Greedy => chunk::MeshingMode::Naive,
+                };
+                self.chunks.set_meshing_mode(meshing_mode);
+                println!("Meshing mode: {:?}", meshing_mode);
+            }
+
+            winit::event::VirtualKeyCode::Key1 => self.select_voxel(0),
+            winit::event::VirtualKeyCode::Key2 => self.select_voxel(1),
+            winit::event::VirtualKeyCode::Key3 => self.select_voxel(2),
+            winit::event::VirtualKeyCode::Key4 => self.select_voxel(3),
+            winit::event::VirtualKeyCode::Key5 => self.select_voxel(4),
+            winit::event::VirtualKeyCode::Key6 => self.select_voxel(5),
+            winit::event::VirtualKeyCode::Key7 => self.select_voxel(6),
+            winit::event::VirtualKeyCode::Key8 => self.select_voxel(7),
+            winit::event::VirtualKeyCode::Key9 => self.select_voxel(8),
+
+            //Swing the sun around the world
+            winit::event::VirtualKeyCode::L => {
+                let rotation = glam::Quat::from_rotation_y(15f32.to_radians());
+                let sun_direction = rotation * self.light.get_sun_direction();
+                self.set_light(light::Light::new(sun_direction, self.light.sun_color, self.light.ambient));
+            }
+
+            winit::event::VirtualKeyCode::F3 => {
+                let (chunk_count, bytes) = self.chunks.memory_usage();
+                println!(
+                    "Chunk voxels: {} chunks using {:.1} KiB ({:.1} KiB uncompressed)",
+                    chunk_count,
+                    bytes as f32 / 1024.,
+                    (chunk_count * chunk::ChunkVoxels::dense_memory_usage(self.chunks.get_chunk_size())) as f32 / 1024.,
+                );
+                println!(
+                    "Chunk meshes: {} drawn, {} culled",
+                    self.chunk_draw_stats.drawn,
+                    self.chunk_draw_stats.culled,
+                );
+                println!(
+                    "Translucent chunk meshes: {} drawn, {} culled",
+                    self.translucent_draw_stats.drawn,
+                    self.translucent_draw_stats.culled,
+                );
+
+                let (vertex_stats, index_stats) = self.chunk_meshes.get_stats();
+                for (name, stats) in [("Vertex", vertex_stats), ("Index", index_stats)] {
+                    println!(
+                        "{} arena: {:.1} of {:.1} KiB used, {} free ranges ({:.0}% fragmented)",
+                        name,
+                        stats.used as f32 / 1024.,
+                        stats.capacity as f32 / 1024.,
+                        stats.free_ranges,
+                        stats.fragmentation() * 100.,
+                    );
+                }
+            }
+
+            winit::event::VirtualKeyCode::F5 => {
+                match self.chunks.save_to_dir(std::path::Path::new(SAVE_DIR)) {
+                    Ok(count) => println!("Saved {} chunks to {}", count, SAVE_DIR),
+                    Err(e) => eprintln!("Failed to save world: {}", e),
+                }
+            }
+            winit::event::VirtualKeyCode::F9 => {
+                match self.chunks.load_from_dir(std::path::Path::new(SAVE_DIR)) {
+                    Ok(count) => println!("Loaded {} chunks from {}", count, SAVE_DIR),
+                    Err(e) => eprintln!("Failed to load world: {}", e),
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    pub fn update(&mut self, dt: std::time::Duration) {
+        self.tick_accumulator += dt.min(MAX_FRAME_DURATION);
+
+        while self.tick_accumulator >= TICK_DURATION {
+            self.tick_accumulator -= TICK_DURATION;
+            self.tick();
+        }
+
+        //Draw the camera part way between the last two ticks
+        let alpha = self.tick_accumulator.as_secs_f32() / TICK_DURATION.as_secs_f32();
+        let render_camera = self.previous_camera.lerp(&self.camera, alpha);
+
+        self.chunks.update(&self.device, &self.queue, &mut self.chunk_meshes, render_camera.get_position());
 
         let camera_uniform =
-            camera::CameraUniform::from_camera(&self.camera, &self.camera_projection);
+            camera::CameraUniform::from_camera(&render_camera, &self.camera_projection);
+        self.camera_frustum = camera::Frustum::from_view_proj(camera_uniform.get_view_proj());
+        self.camera_position = render_camera.get_position();
         self.queue.write_buffer(
             &self.camera_buffer,
             0,
@@ -329,6 +631,13 @@ impl State {
         );
     }
 
+    fn tick(&mut self) {
+        self.previous_camera = self.camera;
+
+        self.camera_controller
+            .update(&mut self.camera, &self.input_controller, TICK_DURATION);
+    }
+
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output
@@ -341,6 +650,12 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
+        //Draws for the opaque chunks are written out before the render pass starts, as the
+        //arena can't be changed while the pass is using it
+        let (opaque_meshes, chunk_draw_stats) = self.chunks.get_opaque_meshes(&self.camera_frustum);
+        let indirect_batches = self.chunk_meshes.write_indirect_draws(&self.device, &self.queue, &opaque_meshes);
+        self.chunk_draw_stats = chunk_draw_stats;
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -369,7 +684,24 @@ impl State {
 
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
-            chunk::DrawChunk::draw_chunks(&mut render_pass, &self.chunks);
+            render_pass.set_bind_group(1, &self.light_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.palette_bind_group, &[]);
+            chunk::DrawChunk::draw_chunks(
+                &mut render_pass,
+                &opaque_meshes,
+                &self.chunk_meshes,
+                indirect_batches.as_deref(),
+            );
+
+            //Translucent voxels go last so whatever is behind them has already been drawn
+            render_pass.set_pipeline(&self.translucent_pipeline);
+            self.translucent_draw_stats = chunk::DrawChunk::draw_translucent_chunks(
+                &mut render_pass,
+                &self.chunks,
+                &self.chunk_meshes,
+                &self.camera_frustum,
+                self.camera_position,
+            );
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 //====================================================================
 
 use winit::{
@@ -17,8 +19,10 @@ pub async fn run() {
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
-    let mut state = core::state::State::new(&window).await;
+    let mut state = core::state::State::new(&window, chunk_size_from_args()).await;
+
 
+    let target_frame_time = target_fps_from_args().map(|fps| std::time::Duration::from_secs_f64(1.0 / fps));
 
     let mut last_update_inst = std::time::Instant::now();
     let mut last_frame_inst = std::time::Instant::now();
@@ -32,8 +36,6 @@ pub async fn run() {
 
     let mut debug_accum_time = 0.0;
 
-    const TARGET_FPS: f64 = 75.;
-
 
 
     event_loop.run(move |event, _, control_flow| match event {
@@ -42,10 +44,34 @@ pub async fn run() {
 
         //Control Events go here
 
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::Tab),
+                            ..
+                        },
+                    ..
+                },
+            window_id,
+        } if window_id == window.id() => state.toggle_cursor_grab(&window),
+
+        Event::WindowEvent {
+            event: WindowEvent::Focused(false),
+            window_id,
+        } if window_id == window.id() => state.set_cursor_grab(&window, false),
+
+        Event::DeviceEvent {
+            event: DeviceEvent::MouseMotion { delta },
+            ..
+        } => state.mouse_motion(delta),
+
         Event::WindowEvent {
             ref event,
             window_id,
-        } if window_id == window.id() => if !state.input(event) {
+        } if window_id == window.id() && !state.input(event) => {
             match event {
 
                 //________________________________________
@@ -83,10 +109,11 @@ pub async fn run() {
 
         //--------------------------------------------------
 
-        Event::RedrawRequested(window_id) => if window_id == window.id() {
-            state.update();
+        Event::RedrawRequested(window_id) if window_id == window.id() => {
+            let frame_duration = last_frame_inst.elapsed();
+            state.update(frame_duration);
 
-            let last_elapsed = last_frame_inst.elapsed().as_secs_f32();
+            let last_elapsed = frame_duration.as_secs_f32();
 
             frame_time_accum_time += last_elapsed;
             fps_accum_time += last_elapsed;
@@ -142,24 +169,21 @@ pub async fn run() {
 
         //--------------------------------------------------
 
-        Event::MainEventsCleared => {
-            window.request_redraw();
-        }
-        
-        /*
-        Event::RedrawEventsCleared => {
-            
-            let target_frametime = std::time::Duration::from_secs_f64(1.0 / TARGET_FPS);
-            let time_since_last_frame = last_update_inst.elapsed();
-            if time_since_last_frame >= target_frametime {
-                window.request_redraw();
-                last_update_inst = std::time::Instant::now();
-            } else {
-                *control_flow = ControlFlow::WaitUntil(
-                    std::time::Instant::now() + target_frametime - time_since_last_frame,
-                );
+        Event::MainEventsCleared => match target_frame_time {
+            Some(target_frame_time) => {
+                let time_since_last_frame = last_update_inst.elapsed();
+                if time_since_last_frame >= target_frame_time {
+                    window.request_redraw();
+                    last_update_inst = std::time::Instant::now();
+                    *control_flow = ControlFlow::Poll;
+                } else {
+                    *control_flow = ControlFlow::WaitUntil(
+                        std::time::Instant::now() + target_frame_time - time_since_last_frame,
+                    );
+                }
             }
-        }*/
+            None => window.request_redraw(),
+        }
 
         //--------------------------------------------------
 
@@ -171,6 +195,63 @@ pub async fn run() {
 
 //====================================================================
 
+const TARGET_FPS: f64 = 75.;
+
+//Frame limit from "--fps <value>", 0 turns the limiter off
+fn target_fps_from_args() -> Option<f64> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--fps").skip(1);
+
+    let target_fps = match args.next() {
+        Some(value) => match value.parse::<f64>() {
+            Ok(fps) => fps,
+            Err(_) => {
+                eprintln!("Invalid --fps value '{}', using {}", value, TARGET_FPS);
+                TARGET_FPS
+            }
+        },
+        None => TARGET_FPS,
+    };
+
+    if target_fps > 0. {
+        Some(target_fps)
+    } else {
+        None
+    }
+}
+
+//Chunk size of the world, either one size for every axis ("--chunk-size 16") or
+//width x height x depth ("--chunk-size 16x32x16")
+fn chunk_size_from_args() -> voxels::chunk::ChunkSize {
+    let default_size = voxels::chunk::DEFAULT_CHUNK_SIZE;
+
+    let value = match std::env::args().skip_while(|arg| arg != "--chunk-size").nth(1) {
+        Some(value) => value,
+        None => return default_size,
+    };
+
+    let sizes = value.split('x').map(|size| size.parse::<u8>().ok()).collect::<Option<Vec<u8>>>();
+    let chunk_size = match sizes.as_deref() {
+        Some(&[size]) => voxels::chunk::ChunkSize::new(size, size, size),
+        Some(&[width, height, depth]) => voxels::chunk::ChunkSize::new(width, height, depth),
+        _ => None,
+    };
+
+    match chunk_size {
+        Some(chunk_size) => chunk_size,
+        None => {
+            eprintln!(
+                "Invalid --chunk-size value '{}', each axis must be 1-{}. Using {:?}",
+                value,
+                voxels::chunk::ChunkSize::MAX,
+                default_size,
+            );
+            default_size
+        }
+    }
+}
+
+//====================================================================
+
 fn main() {
     pollster::block_on(run());
 }
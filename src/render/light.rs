@@ -0,0 +1,46 @@
+//====================================================================
+
+//====================================================================
+
+pub struct Light {
+    //Direction the sunlight travels in
+    sun_direction: glam::Vec3,
+    pub sun_color: glam::Vec3,
+    //Light applied to every surface, including ones facing away from the sun
+    pub ambient: f32,
+}
+
+impl Light {
+    pub fn new(sun_direction: glam::Vec3, sun_color: glam::Vec3, ambient: f32) -> Self {
+        Self {
+            sun_direction: sun_direction.normalize(),
+            sun_color,
+            ambient,
+        }
+    }
+
+    pub fn get_sun_direction(&self) -> glam::Vec3 {
+        return self.sun_direction;
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    sun_direction: [f32; 3],
+    ambient: f32,
+    sun_color: [f32; 3],
+    _padding: f32,
+}
+impl LightUniform {
+    pub fn from_light(light: &Light) -> Self {
+        Self {
+            sun_direction: light.sun_direction.to_array(),
+            ambient: light.ambient,
+            sun_color: light.sun_color.to_array(),
+            _padding: 0.,
+        }
+    }
+}
+
+//====================================================================
@@ -1 +1,3 @@
+pub mod light;
+pub mod palette;
 pub mod texture;
\ No newline at end of file
@@ -0,0 +1,39 @@
+//====================================================================
+
+use crate::voxels::registry;
+
+//====================================================================
+
+//Colour of every voxel type for the shader, indexed by voxel id. Chunk vertices only
+//carry the id, so this is where their colour, alpha, emissive level and colour variation
+//come from.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PaletteUniform {
+    colors: [[f32; 4]; 256],
+    //Emissive level and colour variance, uniform array elements take 16 bytes regardless
+    properties: [[f32; 4]; 256],
+    //World seed split into its low and high halves, hashed with each voxel's position
+    //so its colour variation is the same every run
+    seed: [u32; 4],
+}
+impl PaletteUniform {
+    pub fn from_registry(registry: &registry::VoxelRegistry, seed: u64) -> Self {
+        let mut colors = [[0.; 4]; 256];
+        let mut properties = [[0.; 4]; 256];
+
+        for voxel in registry.iter() {
+            let [r, g, b] = voxel.color;
+            colors[voxel.id.get() as usize] = [r, g, b, voxel.alpha];
+            properties[voxel.id.get() as usize] = [voxel.emissive, voxel.color_variance, 0., 0.];
+        }
+
+        Self {
+            colors,
+            properties,
+            seed: [seed as u32, (seed >> 32) as u32, 0, 0],
+        }
+    }
+}
+
+//====================================================================
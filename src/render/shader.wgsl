@@ -1,9 +1,15 @@
 //====================================================================
 //Vertex Shader
 
+//Packed chunk vertex, see model::Vertex for the layout
 struct VertexIn {
-    [[location(0)]] pos: vec3<f32>;
-    [[location(1)]] color: vec3<f32>;
+    [[location(0)]] data: u32;
+};
+
+//Per chunk, see mesh_arena::ChunkInstance
+struct ChunkIn {
+    [[location(1)]] origin: vec3<f32>;
+    [[location(2)]] voxel_size: vec3<f32>;
 };
 
 // struct InstanceIn {
@@ -20,7 +26,12 @@ struct VertexIn {
 struct VertexOut {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] color: vec3<f32>;
-    
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] emissive: f32;
+    [[location(3)]] alpha: f32;
+    [[location(4)]] world_position: vec3<f32>;
+    [[location(5)]] color_variance: f32;
+    [[location(6)]] voxel_size: vec3<f32>;
 };
 
 //--------------------------------------------------
@@ -31,11 +42,31 @@ struct CameraUniform {
 [[group(0), binding(0)]]
 var<uniform> u_camera: CameraUniform;
 
+struct LightUniform {
+    sun_direction: vec3<f32>;
+    ambient: f32;
+    sun_color: vec3<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> u_light: LightUniform;
+
+struct PaletteUniform {
+    //Indexed by voxel id
+    colors: array<vec4<f32>, 256>;
+    //Emissive level and colour variance
+    properties: array<vec4<f32>, 256>;
+    //Low and high halves of the world seed
+    seed: vec4<u32>;
+};
+[[group(2), binding(0)]]
+var<uniform> u_palette: PaletteUniform;
+
 //--------------------------------------------------
 
 [[stage(vertex)]]
 fn vs_main(
     vertex_in: VertexIn,
+    chunk_in: ChunkIn,
     //instance_in: InstanceIn,
 ) -> VertexOut {
 
@@ -46,23 +77,90 @@ fn vs_main(
     //     instance_in.transform_3,
     // );
 
+    let data = vertex_in.data;
+    let corner = vec3<u32>(
+        data & 63u,
+        (data >> 6u) & 63u,
+        (data >> 12u) & 63u,
+    );
+    let side = (data >> 18u) & 7u;
+    let ao = (data >> 21u) & 3u;
+    let voxel_id = (data >> 23u) & 255u;
+
+    //In the same order as voxel::Side
+    var normals = array<vec3<f32>, 6>(
+        vec3<f32>(0., 1., 0.),  //Top
+        vec3<f32>(0., -1., 0.), //Bottom
+        vec3<f32>(0., 0., 1.),  //Front
+        vec3<f32>(0., 0., -1.), //Back
+        vec3<f32>(-1., 0., 0.), //Left
+        vec3<f32>(1., 0., 0.),  //Right
+    );
+
+    //Colour multiplier for each ambient occlusion level, from fully occluded to open
+    var ao_brightness = array<f32, 4>(0.45, 0.65, 0.82, 1.);
+
+    let pos = chunk_in.origin + vec3<f32>(corner) * chunk_in.voxel_size;
+    let palette_color = u_palette.colors[voxel_id];
+
     var out: VertexOut;
 
-    out.clip_position = u_camera.view_proj * vec4<f32>(vertex_in.pos, 1.,);
+    out.clip_position = u_camera.view_proj * vec4<f32>(pos, 1.,);
     //out.clip_position = u_camera.view_proj * instance_transform * vec4<f32>(vertex_in.pos, 1.,);
-    out.color = vertex_in.color;
+    out.color = palette_color.rgb * ao_brightness[ao];
+    out.normal = normals[side];
+    out.emissive = u_palette.properties[voxel_id].x;
+    out.alpha = palette_color.a;
+    out.world_position = pos;
+    out.color_variance = u_palette.properties[voxel_id].y;
+    out.voxel_size = chunk_in.voxel_size;
 
     return out;
 }
 
 //====================================================================
+//Fragment Shader
+
+//Hashes a voxel position with the world seed, returns 0..1
+fn hash_voxel(voxel: vec3<i32>) -> f32 {
+    var h = u_palette.seed.x
+        ^ (u_palette.seed.y * 0x27D4EB2Fu)
+        ^ (bitcast<u32>(voxel.x) * 0x9E3779B1u)
+        ^ (bitcast<u32>(voxel.y) * 0x85EBCA77u)
+        ^ (bitcast<u32>(voxel.z) * 0xC2B2AE3Du);
+
+    //lowbias32 finalizer
+    h = (h ^ (h >> 16u)) * 0x7FEB352Du;
+    h = (h ^ (h >> 15u)) * 0x846CA68Bu;
+    h = h ^ (h >> 16u);
+
+    return f32(h >> 8u) / 16777216.;
+}
 
 [[stage(fragment)]]
 fn fs_main(
     in: VertexOut,
 ) -> [[location(0)]] vec4<f32> {
 
-    return vec4<f32>(in.color, 1.0);
+    //Greedy faces span many voxels, so the voxel each fragment belongs to is found by
+    //stepping half a voxel back from the face. Voxel centres sit on whole multiples of
+    //the voxel size.
+    let voxel_size = in.voxel_size;
+    let inside = in.world_position - normalize(in.normal) * voxel_size * 0.5;
+    let voxel = vec3<i32>(floor(inside / voxel_size + vec3<f32>(0.5)));
+
+    //Colour variation only ever darkens the palette colour
+    let shade = 1. - hash_voxel(voxel) * in.color_variance;
+
+    //Lambert lighting from the sun on top of a flat ambient term
+    let diffuse = max(dot(normalize(in.normal), -u_light.sun_direction), 0.0);
+    var lighting = vec3<f32>(u_light.ambient) + u_light.sun_color * diffuse;
+
+    //Emissive voxels never drop below their own brightness
+    lighting = max(lighting, vec3<f32>(in.emissive));
+
+    //Only the translucent pipeline blends, the opaque one replaces whatever is there
+    return vec4<f32>(in.color * shade * lighting, in.alpha);
 
 }
 
@@ -2,6 +2,7 @@
 
 //====================================================================
 
+#[allow(unused)]
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -1,8 +1,9 @@
 //====================================================================
 
-use wgpu::util::DeviceExt;
+use std::sync::Arc;
 
-use super::{model, voxel};
+use super::{mesh_arena, model, region, registry, terrain, voxel, worker};
+use crate::core::camera;
 
 //====================================================================
 
@@ -10,71 +11,867 @@ use super::{model, voxel};
 // pub const TOTAL_CHUNKS_Y: u16 = 1;
 // pub const TOTAL_CHUNKS_Z: u16 = 10;
 
-pub const CHUNK_WIDTH: u8 = 10;
-pub const CHUNK_HEIGHT: u8 = 10;
-pub const CHUNK_DEPTH: u8 = 10;
+pub const DEFAULT_CHUNK_SIZE: ChunkSize = ChunkSize { width: 10, height: 10, depth: 10 };
+
+//Far enough out that distant chunks use every level of detail
+pub const CHUNK_SPAWN_RANGE: u8 = 12;
+pub const CHUNK_SPAWN_HEIGHT: u8 = 3;
+
+//Chunks are loaded inside the load radius and only unloaded once they fall outside the
+//(larger) unload radius, so chunks at the edge don't flicker in and out.
+pub const CHUNK_LOAD_RADIUS: u8 = CHUNK_SPAWN_RANGE;
+pub const CHUNK_UNLOAD_RADIUS: u8 = CHUNK_SPAWN_RANGE + 2;
+pub const MAX_CHUNK_BUILDS_PER_FRAME: usize = 8;
+
+//Chunks past 3/8, 5/8 and 7/8 of the load radius are meshed at the next level of detail.
+//Each level halves the resolution, so the last one is 8 voxels to a cell.
+pub const LOD_FRACTIONS: [u8; 3] = [3, 5, 7];
+//How many chunks closer than its threshold a chunk has to be before it goes back to a
+//finer level, so moving back and forth over a chunk border doesn't remesh a whole ring
+pub const LOD_HYSTERESIS: u8 = 1;
+
+//Dimensions of every chunk in a world, set when the world is created. Positions inside
+//a chunk have to fit in a voxel::LocalCoord, so each axis is at most 32 voxels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSize {
+    width: u8,
+    height: u8,
+    depth: u8,
+}
+
+impl ChunkSize {
+    pub const MAX: u8 = voxel::LocalCoord::MAX_VALUE + 1;
+
+    pub fn new(width: u8, height: u8, depth: u8) -> Option<Self> {
+        let valid = |value: u8| (1..=Self::MAX).contains(&value);
+
+        if valid(width) && valid(height) && valid(depth) {
+            Some(Self { width, height, depth })
+        } else {
+            None
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+    pub fn depth(&self) -> usize {
+        self.depth as usize
+    }
+
+    pub fn to_array(self) -> [usize; 3] {
+        [self.width(), self.height(), self.depth()]
+    }
+
+    pub fn as_ivec3(&self) -> glam::IVec3 {
+        glam::IVec3::new(self.width as i32, self.height as i32, self.depth as i32)
+    }
+
+    pub fn volume(&self) -> usize {
+        self.width() * self.height() * self.depth()
+    }
+
+    //World space box around every voxel of the chunk at chunk_pos. Voxels are centred
+    //on their position, so the box starts half a voxel before the chunk origin.
+    pub fn world_bounds(&self, chunk_pos: glam::IVec3) -> (glam::Vec3, glam::Vec3) {
+        let voxel_size = glam::Vec3::new(voxel::VOXEL_WIDTH, voxel::VOXEL_HEIGHT, voxel::VOXEL_DEPTH);
+
+        let min = (chunk_pos * self.as_ivec3()).as_vec3() * voxel_size - voxel::HALF_VOXEL_SIZE;
+        let max = min + self.as_ivec3().as_vec3() * voxel_size;
+
+        (min, max)
+    }
+
+    //LocalCoord goes up to MAX_VALUE on every axis, the chunk may stop short of that
+    pub fn contains(&self, coord: voxel::LocalCoord) -> bool {
+        coord.x() < self.width && coord.y() < self.height && coord.z() < self.depth
+    }
+
+    //Position inside the chunk, or None if it's outside of it
+    pub fn local_coord(&self, pos: glam::IVec3) -> Option<voxel::LocalCoord> {
+        if pos.cmpge(glam::IVec3::ZERO).all() && pos.cmplt(self.as_ivec3()).all() {
+            voxel::LocalCoord::from_ivec3(pos)
+        } else {
+            None
+        }
+    }
+
+    //Splits a position into the offset of the chunk containing it and the position inside
+    //that chunk. Works for negative positions too.
+    pub fn wrap(&self, pos: glam::IVec3) -> (glam::IVec3, voxel::LocalCoord) {
+        let size = self.as_ivec3();
+
+        let chunk_offset = glam::IVec3::new(
+            pos.x.div_euclid(size.x),
+            pos.y.div_euclid(size.y),
+            pos.z.div_euclid(size.z),
+        );
+
+        //Sizes are checked against LocalCoord in ChunkSize::new so this always fits
+        let local_coord = voxel::LocalCoord::new(
+            pos.x.rem_euclid(size.x) as u8,
+            pos.y.rem_euclid(size.y) as u8,
+            pos.z.rem_euclid(size.z) as u8,
+        ).expect("Chunk size is larger than a LocalCoord can hold");
+
+        (chunk_offset, local_coord)
+    }
+
+    //Size of the chunk with every axis divided by factor, rounding up
+    pub fn downsampled(self, factor: u8) -> Self {
+        Self {
+            width: self.width.div_ceil(factor),
+            height: self.height.div_ceil(factor),
+            depth: self.depth.div_ceil(factor),
+        }
+    }
+
+    //Every position inside the chunk, x then y then z
+    pub fn coords(self) -> impl Iterator<Item = voxel::LocalCoord> {
+        (0..self.width).flat_map(move |x| {
+            (0..self.height).flat_map(move |y| (0..self.depth).filter_map(move |z| voxel::LocalCoord::new(x, y, z)))
+        })
+    }
+}
+
+//====================================================================
+
+//Voxels of a chunk stored as a palette of the voxels it contains, plus the palette index
+//of every voxel packed into as few bits as the palette needs. A chunk of a single voxel
+//(all air or all stone) is just the palette and stores no indices at all.
+#[derive(Clone)]
+pub struct ChunkVoxels {
+    size: ChunkSize,
+    palette: Vec<Option<voxel::VoxelId>>,
+    //0, 1, 2, 4 or 8 so an index never spans two words
+    bits_per_index: u8,
+    indices: Vec<u64>,
+}
+
+impl ChunkVoxels {
+    pub fn empty(size: ChunkSize) -> Self {
+        Self::filled(size, None)
+    }
+
+    pub fn filled(size: ChunkSize, voxel: Option<voxel::VoxelId>) -> Self {
+        Self {
+            size,
+            palette: vec![voxel],
+            bits_per_index: 0,
+            indices: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, coord: voxel::LocalCoord) -> Option<voxel::VoxelId> {
+        if self.bits_per_index == 0 {
+            return self.palette[0];
+        }
+        self.palette[self.get_palette_index(self.index(coord))]
+    }
+
+    pub fn set(&mut self, coord: voxel::LocalCoord, voxel: Option<voxel::VoxelId>) {
+        let palette_index = match self.palette.iter().position(|entry| *entry == voxel) {
+            Some(palette_index) => palette_index,
+            None => {
+                self.palette.push(voxel);
+                if self.palette.len() > 1 << self.bits_per_index {
+                    self.repack(Self::bits_for_palette(self.palette.len()));
+                }
+                self.palette.len() - 1
+            }
+        };
+
+        //Still a single voxel and it's the one being set
+        if self.bits_per_index == 0 {
+            return;
+        }
+
+        self.set_palette_index(self.index(coord), palette_index);
+    }
 
-pub const CHUNK_SPAWN_RANGE: u8 = 5;
+    //Drops palette entries that are no longer used, shrinking the indices to match
+    pub fn compact(&mut self) {
+        if self.bits_per_index == 0 {
+            return;
+        }
+
+        let mut used = vec![false; self.palette.len()];
+        for i in 0..self.size.volume() {
+            used[self.get_palette_index(i)] = true;
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+
+        let mut compacted = Self::filled(self.size, self.palette[used.iter().position(|used| *used).unwrap_or(0)]);
+        for coord in self.size.coords() {
+            compacted.set(coord, self.get(coord));
+        }
+
+        *self = compacted;
+    }
+
+    //Copy of the chunk at a lower resolution, each voxel standing in for a cell of factor
+    //voxels along each axis. Cells at least half full are filled with whichever voxel type
+    //fills the most of them, so surface layers survive. Cells at the far edges are cut
+    //short when the chunk size isn't a multiple of factor.
+    pub fn downsample(&self, factor: u8) -> Self {
+        let size = self.size.downsampled(factor);
+        let mut downsampled = Self::empty(size);
+
+        for coord in size.coords() {
+            let min = coord.as_ivec3() * factor as i32;
+            let max = (min + glam::IVec3::splat(factor as i32)).min(self.size.as_ivec3());
+
+            let mut counts: Vec<(voxel::VoxelId, usize)> = Vec::new();
+            let mut filled = 0;
+
+            for x in min.x..max.x {
+                for y in min.y..max.y {
+                    for z in min.z..max.z {
+                        let voxel = self.size.local_coord(glam::IVec3::new(x, y, z)).and_then(|coord| self.get(coord));
+                        let voxel = match voxel {
+                            Some(voxel) => voxel,
+                            None => continue,
+                        };
+
+                        filled += 1;
+                        match counts.iter_mut().find(|(id, _)| *id == voxel) {
+                            Some((_, count)) => *count += 1,
+                            None => counts.push((voxel, 1)),
+                        }
+                    }
+                }
+            }
+
+            let cell_volume = ((max - min).x * (max - min).y * (max - min).z) as usize;
+            if filled * 2 >= cell_volume {
+                downsampled.set(coord, counts.iter().max_by_key(|(_, count)| *count).map(|(id, _)| *id));
+            }
+        }
+
+        downsampled.compact();
+        downsampled
+    }
+
+    //Heap and inline size in bytes
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.palette.capacity() * std::mem::size_of::<Option<voxel::VoxelId>>()
+            + self.indices.capacity() * std::mem::size_of::<u64>()
+    }
+
+    //Size of the same chunk stored as a plain array of voxels
+    pub fn dense_memory_usage(size: ChunkSize) -> usize {
+        size.volume() * std::mem::size_of::<Option<voxel::VoxelId>>()
+    }
+
+    pub fn get_size(&self) -> ChunkSize {
+        self.size
+    }
+
+    //--------------------------------------------------
+
+    fn index(&self, coord: voxel::LocalCoord) -> usize {
+        debug_assert!(self.size.local_coord(coord.as_ivec3()).is_some(), "{:?} is outside the chunk", coord);
+
+        let (x, y, z) = coord.xyz();
+        (x as usize * self.size.height() + y as usize) * self.size.depth() + z as usize
+    }
+
+    fn bits_for_palette(palette_len: usize) -> u8 {
+        match palette_len {
+            0..=1 => 0,
+            2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        }
+    }
+
+    fn get_palette_index(&self, index: usize) -> usize {
+        let bits = self.bits_per_index as usize;
+        let per_word = 64 / bits;
+
+        let word = self.indices[index / per_word];
+        ((word >> ((index % per_word) * bits)) & ((1 << bits) - 1)) as usize
+    }
+
+    fn set_palette_index(&mut self, index: usize, palette_index: usize) {
+        let bits = self.bits_per_index as usize;
+        let per_word = 64 / bits;
+        let shift = (index % per_word) * bits;
 
-//MAX CHUNK WITH MUST NOT EXCEED 31
+        let word = &mut self.indices[index / per_word];
+        *word &= !(((1 << bits) - 1) << shift);
+        *word |= (palette_index as u64) << shift;
+    }
 
-//Max chunk w/h/d = 0..31   -> u5
+    //Moves every index over to a new size, keeping their values
+    fn repack(&mut self, bits_per_index: u8) {
+        let old = Self {
+            size: self.size,
+            palette: Vec::new(),
+            bits_per_index: self.bits_per_index,
+            indices: std::mem::take(&mut self.indices),
+        };
 
-pub struct ChunkVoxels([[[Option<voxel::Voxel>; CHUNK_DEPTH as usize]; CHUNK_HEIGHT as usize]; CHUNK_WIDTH as usize]);
+        self.bits_per_index = bits_per_index;
+        self.indices = vec![0; self.size.volume().div_ceil(64 / bits_per_index as usize)];
+
+        if old.bits_per_index == 0 {
+            return;
+        }
+        for i in 0..self.size.volume() {
+            self.set_palette_index(i, old.get_palette_index(i));
+        }
+    }
+}
+
+//====================================================================
+
+//Voxel data of the 26 chunks surrounding a chunk, indexed by their offset from it.
+//Missing neighbours (not spawned yet) are None and their voxels are treated as empty.
+//Holds shared references so it can be sent to the chunk worker threads.
+pub struct ChunkNeighbours([Option<Arc<ChunkVoxels>>; 27]);
+
+impl ChunkNeighbours {
+    //Neighbours at a different level of detail are left out too. Their surface won't line
+    //up with this chunk's, so the faces against them are kept as a skirt to cover the gap.
+    pub fn new(chunks: &std::collections::HashMap<glam::IVec3, Chunk>, chunk_pos: glam::IVec3) -> Self {
+        let mut neighbours: [Option<Arc<ChunkVoxels>>; 27] = Default::default();
+
+        let lod = chunks.get(&chunk_pos).map_or(0, |chunk| chunk.lod);
+
+        for offset in Self::offsets() {
+            neighbours[Self::index(offset)] = chunks
+                .get(&(chunk_pos + offset))
+                .filter(|chunk| chunk.lod == lod)
+                .map(|chunk| chunk.voxels.clone());
+        }
+
+        Self(neighbours)
+    }
+
+    pub fn downsample(&self, factor: u8) -> Self {
+        Self(self.0.each_ref().map(|neighbour| {
+            neighbour.as_ref().map(|voxels| Arc::new(voxels.downsample(factor)))
+        }))
+    }
+
+    //Offsets to every surrounding chunk, including edges and corners
+    pub fn offsets() -> impl Iterator<Item = glam::IVec3> {
+        (-1..=1).flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| glam::IVec3::new(x, y, z))))
+            .filter(|offset| *offset != glam::IVec3::ZERO)
+    }
+
+    fn index(offset: glam::IVec3) -> usize {
+        ((offset.x + 1) * 9 + (offset.y + 1) * 3 + (offset.z + 1)) as usize
+    }
+
+    pub fn get(&self, offset: glam::IVec3) -> Option<&ChunkVoxels> {
+        if offset.abs().max_element() > 1 {
+            return None;
+        }
+        self.0[Self::index(offset)].as_deref()
+    }
+}
+
+//====================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshingMode {
+    //One quad for every visible voxel face
+    Naive,
+    //Coplanar faces of the same voxel type are merged into larger quads
+    Greedy,
+}
 
 //====================================================================
 
 pub struct ChunkCollection {
     pub chunks: std::collections::HashMap<glam::IVec3, Chunk>,
+    chunk_size: ChunkSize,
+    meshing_mode: MeshingMode,
+    registry: Arc<registry::VoxelRegistry>,
+
+    workers: worker::ChunkWorkers,
+    //Chunks waiting on a generation job, along with the ticket of that job
+    pending_chunks: std::collections::HashMap<glam::IVec3, worker::JobTicket>,
+    //Voxels of edited chunks that aren't loaded. Used instead of the generator when the
+    //chunk is loaded again, and written out by save_to_dir.
+    stored_chunks: std::collections::HashMap<glam::IVec3, Arc<ChunkVoxels>>,
+
+    pub load_radius: u8,
+    pub unload_radius: u8,
+    pub max_builds_per_frame: usize,
 }
-impl<'a> ChunkCollection {
-    pub fn new() -> Self {
+impl ChunkCollection {
+    pub fn new(
+        chunk_size: ChunkSize,
+        meshing_mode: MeshingMode,
+        generator: Box<dyn terrain::TerrainGenerator>,
+        registry: Arc<registry::VoxelRegistry>,
+    ) -> Self {
         let chunks = std::collections::HashMap::new();
 
 
         Self {
             chunks,
+            chunk_size,
+            meshing_mode,
+            registry: registry.clone(),
+
+            workers: worker::ChunkWorkers::new(chunk_size, Arc::from(generator), registry),
+            pending_chunks: std::collections::HashMap::new(),
+            stored_chunks: std::collections::HashMap::new(),
+
+            load_radius: CHUNK_LOAD_RADIUS,
+            unload_radius: CHUNK_UNLOAD_RADIUS,
+            max_builds_per_frame: MAX_CHUNK_BUILDS_PER_FRAME,
         }
     }
 
-    pub fn spawn_chunks_in_range(&mut self, device: &wgpu::Device, pos: glam::IVec3) {
+    pub fn get_meshing_mode(&self) -> MeshingMode {
+        self.meshing_mode
+    }
 
-        let start_pos = glam::IVec3::new(
-            pos.x - CHUNK_SPAWN_RANGE as i32,
-            0,
-            pos.z - CHUNK_SPAWN_RANGE as i32,
-        );
+    pub fn get_chunk_size(&self) -> ChunkSize {
+        self.chunk_size
+    }
 
-        let end_pos = glam::IVec3::new(
-            pos.x + CHUNK_SPAWN_RANGE as i32,
-            1,
-            pos.z + CHUNK_SPAWN_RANGE as i32,
-        );
+    pub fn get_registry(&self) -> &registry::VoxelRegistry {
+        &self.registry
+    }
+
+    pub fn set_meshing_mode(&mut self, meshing_mode: MeshingMode) {
+        if self.meshing_mode == meshing_mode {
+            return;
+        }
+
+        self.meshing_mode = meshing_mode;
+        for chunk in self.chunks.values_mut() {
+            chunk.dirty = true;
+        }
+    }
+
+    //Generated chunks are left dirty and get their mesh once a worker has built it
+    fn insert_chunk(&mut self, pos: glam::IVec3, voxels: Arc<ChunkVoxels>, edited: bool) {
+        match self.chunks.entry(pos) {
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(Chunk::new(pos, voxels, edited));
+            }
+            std::collections::hash_map::Entry::Occupied(_) => {
+                println!("Trying to spawn pre-existing chunk");
+                return;
+            }
+        }
+
+        //The new chunk may hide border faces (or shade the corners) of the chunks around it
+        self.mark_neighbours_dirty(pos);
+    }
+
+    //--------------------------------------------------
+
+    //Streams chunks around the camera. Finished worker jobs are collected, far chunks are
+    //unloaded, then jobs are sent out for the closest missing and dirty chunks, up to
+    //max_builds_per_frame of each. Chunk meshes are kept in the given arena.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mesh_arena: &mut mesh_arena::ChunkMeshArena,
+        camera_position: glam::Vec3,
+    ) {
+        let (centre, _) = self.world_to_local(Self::position_to_world(camera_position));
 
-        for x in start_pos.x..end_pos.x {
-            //println!("x = {}", {x});
-            for y in start_pos.y..end_pos.y {
-                //println!("y = {}", {y});
-                for z in start_pos.z..end_pos.z {
-                    //println!("z = {}", {z});
-                    self.spawn_chunk(device, glam::IVec3::new(x, y, z));
+        self.receive_job_results(device, queue, mesh_arena);
+
+        for chunk in self.unload_far_chunks(centre) {
+            for mesh in [chunk.mesh, chunk.translucent_mesh].into_iter().flatten() {
+                mesh_arena.free(mesh);
+            }
+        }
+        self.request_near_chunks(centre);
+        self.update_chunk_lods(centre);
+        self.request_dirty_chunk_meshes(centre);
+    }
+
+    fn receive_job_results(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mesh_arena: &mut mesh_arena::ChunkMeshArena,
+    ) {
+        let results: Vec<worker::ChunkJobResult> = self.workers.try_iter().collect();
+
+        for result in results {
+            match result {
+                worker::ChunkJobResult::Generated { ticket, chunk_pos, voxels } => {
+                    self.receive_generated_chunk(ticket, chunk_pos, *voxels);
+                }
+
+                worker::ChunkJobResult::Meshed { ticket, chunk_pos, mesh_data } => {
+                    let chunk = match self.chunks.get_mut(&chunk_pos) {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+
+                    //Chunk was unloaded and spawned again since the job was sent
+                    if chunk.pending_mesh != Some(ticket) {
+                        continue;
+                    }
+
+                    //If the chunk changed while this was meshing it is still dirty and will
+                    //be sent off again, the older mesh is shown in the meantime.
+                    let voxel_size = glam::Vec3::new(voxel::VOXEL_WIDTH, voxel::VOXEL_HEIGHT, voxel::VOXEL_DEPTH);
+                    let instance = mesh_arena::ChunkInstance::new(chunk.bounds.0, voxel_size);
+
+                    let (vertices, indices) = &mesh_data.opaque;
+                    let mesh = mesh_arena.upload(device, queue, vertices, indices, instance);
+                    if let Some(old_mesh) = chunk.mesh.replace(mesh) {
+                        mesh_arena.free(old_mesh);
+                    }
+
+                    let (vertices, indices) = &mesh_data.translucent;
+                    let mesh = mesh_arena.upload(device, queue, vertices, indices, instance);
+                    if let Some(old_mesh) = chunk.translucent_mesh.replace(mesh) {
+                        mesh_arena.free(old_mesh);
+                    }
+                    chunk.pending_mesh = None;
                 }
             }
         }
     }
 
+    fn receive_generated_chunk(&mut self, ticket: worker::JobTicket, chunk_pos: glam::IVec3, voxels: ChunkVoxels) {
+        //Chunk was unloaded (or requested again) since the job was sent
+        if self.pending_chunks.get(&chunk_pos) != Some(&ticket) {
+            return;
+        }
+
+        self.pending_chunks.remove(&chunk_pos);
+        self.insert_chunk(chunk_pos, Arc::new(voxels), false);
+    }
+
+    fn horizontal_distance(chunk_pos: glam::IVec3, centre: glam::IVec3) -> i32 {
+        (chunk_pos.x - centre.x).abs().max((chunk_pos.z - centre.z).abs())
+    }
+
+    //Unloaded chunks are returned so their meshes can be freed
+    fn unload_far_chunks(&mut self, centre: glam::IVec3) -> Vec<Chunk> {
+        let unload_radius = self.unload_radius as i32;
 
+        let far_chunks: Vec<glam::IVec3> = self.chunks
+            .keys()
+            .filter(|pos| Self::horizontal_distance(**pos, centre) > unload_radius)
+            .copied()
+            .collect();
 
-    pub fn spawn_chunk(&mut self, device: &wgpu::Device, pos: glam::IVec3) {
-        if !self.chunks.contains_key(&pos) {
+        //Results for these will be ignored when they come back
+        self.pending_chunks.retain(|pos, _| Self::horizontal_distance(*pos, centre) <= unload_radius);
 
-            self.chunks.insert(
-                pos,
-                Chunk::new(device, pos),
-            );
+        let mut unloaded_chunks = Vec::with_capacity(far_chunks.len());
+
+        for chunk_pos in far_chunks {
+            if let Some(chunk) = self.chunks.remove(&chunk_pos) {
+                if chunk.edited {
+                    self.stored_chunks.insert(chunk_pos, chunk.voxels.clone());
+                }
+                unloaded_chunks.push(chunk);
+            }
+
+            //Faces that were hidden by the removed chunk are visible again
+            self.mark_neighbours_dirty(chunk_pos);
         }
-        else {
-            println!("Trying to spawn pre-existing chunk");
+
+        unloaded_chunks
+    }
+
+    fn request_near_chunks(&mut self, centre: glam::IVec3) {
+        let load_radius = self.load_radius as i32;
+
+        let mut missing_chunks = Vec::new();
+        for x in (centre.x - load_radius)..=(centre.x + load_radius) {
+            for y in 0..CHUNK_SPAWN_HEIGHT as i32 {
+                for z in (centre.z - load_radius)..=(centre.z + load_radius) {
+                    let chunk_pos = glam::IVec3::new(x, y, z);
+                    if !self.chunks.contains_key(&chunk_pos) && !self.pending_chunks.contains_key(&chunk_pos) {
+                        missing_chunks.push(chunk_pos);
+                    }
+                }
+            }
+        }
+
+        missing_chunks.sort_by_key(|pos| (*pos - centre).dot(*pos - centre));
+
+        for chunk_pos in missing_chunks.into_iter().take(self.max_builds_per_frame) {
+            //Edited chunks come back as they were left instead of being generated again
+            if let Some(voxels) = self.stored_chunks.remove(&chunk_pos) {
+                self.insert_chunk(chunk_pos, voxels, true);
+                continue;
+            }
+
+            let ticket = self.workers.generate(chunk_pos);
+            self.pending_chunks.insert(chunk_pos, ticket);
+        }
+    }
+
+    //Level of detail for a chunk at a distance (in chunks) from the camera, given the level
+    //it is at now. It only changes when the chunk is outside the hysteresis band.
+    fn lod_for_distance(load_radius: u8, current_lod: u8, distance: i32) -> u8 {
+        let lod_distances = LOD_FRACTIONS.map(|fraction| (load_radius as i32 * fraction as i32 / 8).max(1));
+        let lod_at = |distance: i32| lod_distances.iter().filter(|lod_distance| distance >= **lod_distance).count() as u8;
+
+        current_lod.clamp(lod_at(distance), lod_at(distance + LOD_HYSTERESIS as i32))
+    }
+
+    //Chunks that change level are remeshed, along with their neighbours since the skirts
+    //between chunks depend on the levels either side
+    fn update_chunk_lods(&mut self, centre: glam::IVec3) {
+        let mut changed_chunks = Vec::new();
+
+        for (chunk_pos, chunk) in self.chunks.iter_mut() {
+            let distance = Self::horizontal_distance(*chunk_pos, centre);
+            let lod = Self::lod_for_distance(self.load_radius, chunk.lod, distance);
+
+            if chunk.lod != lod {
+                chunk.lod = lod;
+                chunk.dirty = true;
+                changed_chunks.push(*chunk_pos);
+            }
+        }
+
+        for chunk_pos in changed_chunks {
+            self.mark_neighbours_dirty(chunk_pos);
+        }
+    }
+
+    fn request_dirty_chunk_meshes(&mut self, centre: glam::IVec3) {
+        //Chunks already being meshed wait for that job to finish before going again
+        let mut dirty_chunks: Vec<glam::IVec3> = self.chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.dirty && chunk.pending_mesh.is_none())
+            .map(|(pos, _)| *pos)
+            .collect();
+
+        dirty_chunks.sort_by_key(|pos| (*pos - centre).dot(*pos - centre));
+
+        for chunk_pos in dirty_chunks.into_iter().take(self.max_builds_per_frame) {
+            let neighbours = ChunkNeighbours::new(&self.chunks, chunk_pos);
+
+            if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
+                let ticket = self.workers.mesh(chunk_pos, chunk.voxels.clone(), neighbours, self.meshing_mode, chunk.lod);
+
+                chunk.dirty = false;
+                chunk.pending_mesh = Some(ticket);
+            }
+        }
+    }
+
+    //--------------------------------------------------
+
+    //Converts a position in world space to the world voxel position containing it.
+    //Voxels are centred on their position so each one spans half a voxel either side.
+    pub fn position_to_world(position: glam::Vec3) -> glam::IVec3 {
+        glam::IVec3::new(
+            (position.x / voxel::VOXEL_WIDTH + 0.5).floor() as i32,
+            (position.y / voxel::VOXEL_HEIGHT + 0.5).floor() as i32,
+            (position.z / voxel::VOXEL_DEPTH + 0.5).floor() as i32,
+        )
+    }
+
+    //Splits a world voxel position into the position of the chunk containing it and
+    //the voxel's position inside that chunk
+    pub fn world_to_local(&self, world_pos: glam::IVec3) -> (glam::IVec3, voxel::LocalCoord) {
+        self.chunk_size.wrap(world_pos)
+    }
+
+    pub fn get_voxel(&self, world_pos: glam::IVec3) -> Option<voxel::VoxelId> {
+        let (chunk_pos, coord) = self.world_to_local(world_pos);
+
+        let chunk = self.chunks.get(&chunk_pos)?;
+        chunk.voxels.get(coord)
+    }
+
+    //Returns false if the chunk containing the position isn't spawned.
+    //Touched chunks are marked dirty and remeshed by the next update.
+    pub fn set_voxel(&mut self, world_pos: glam::IVec3, voxel: Option<voxel::VoxelId>) -> bool {
+        let (chunk_pos, coord) = self.world_to_local(world_pos);
+
+        match self.chunks.get_mut(&chunk_pos) {
+            Some(chunk) => {
+                if chunk.voxels.get(coord) == voxel {
+                    return true;
+                }
+                //Copies the voxels first if a worker is still meshing the old ones
+                let voxels = Arc::make_mut(&mut chunk.voxels);
+                voxels.set(coord, voxel);
+                voxels.compact();
+                chunk.edited = true;
+            }
+            None => return false,
+        }
+
+        self.mark_voxel_dirty(chunk_pos, coord);
+        true
+    }
+
+    //--------------------------------------------------
+
+    //Only edited chunks are saved, everything else is generated again from the seed.
+    //Chunks already in a region file are kept unless there is a newer copy in memory.
+    //Returns the number of chunks written.
+    pub fn save_to_dir(&self, dir: &std::path::Path) -> std::io::Result<usize> {
+        std::fs::create_dir_all(dir)?;
+
+        let edited_chunks = self.chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.edited)
+            .map(|(pos, chunk)| (*pos, &*chunk.voxels))
+            .chain(self.stored_chunks.iter().map(|(pos, voxels)| (*pos, &**voxels)));
+
+        let mut regions: std::collections::HashMap<glam::IVec3, Vec<(glam::IVec3, &ChunkVoxels)>> =
+            std::collections::HashMap::new();
+
+        for (chunk_pos, voxels) in edited_chunks {
+            regions
+                .entry(region::chunk_to_region(chunk_pos))
+                .or_default()
+                .push((chunk_pos, voxels));
+        }
+
+        let mut chunk_count = 0;
+        for (region_pos, mut chunks) in regions {
+            let path = dir.join(region::region_file_name(region_pos));
+
+            let saved_chunks = match path.exists() {
+                true => region::read_region(&path, region_pos, self.chunk_size)?,
+                false => Vec::new(),
+            };
+            for (chunk_pos, voxels) in saved_chunks.iter() {
+                if !chunks.iter().any(|(pos, _)| pos == chunk_pos) {
+                    chunks.push((*chunk_pos, voxels));
+                }
+            }
+
+            region::write_region(&path, self.chunk_size, &chunks)?;
+            chunk_count += chunks.len();
+        }
+
+        Ok(chunk_count)
+    }
+
+    //Loaded chunks that are in the save are replaced and remeshed, the rest are used
+    //when their chunk is next loaded. Returns the number of chunks read.
+    pub fn load_from_dir(&mut self, dir: &std::path::Path) -> std::io::Result<usize> {
+        let saved_chunks = region::read_regions_in_dir(dir, self.chunk_size)?;
+        let chunk_count = saved_chunks.len();
+
+        for (chunk_pos, voxels) in saved_chunks {
+            let voxels = Arc::new(voxels);
+
+            //Any generation job in flight for this chunk is no longer wanted
+            self.pending_chunks.remove(&chunk_pos);
+
+            match self.chunks.get_mut(&chunk_pos) {
+                Some(chunk) => {
+                    chunk.voxels = voxels;
+                    chunk.edited = true;
+
+                    self.mark_chunk_dirty(chunk_pos);
+                    self.mark_neighbours_dirty(chunk_pos);
+                }
+                None => {
+                    self.stored_chunks.insert(chunk_pos, voxels);
+                }
+            }
+        }
+
+        Ok(chunk_count)
+    }
+
+    //Number of chunk voxel stores held (loaded and stored) and the bytes they use
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let voxels = self.chunks.values().map(|chunk| &chunk.voxels).chain(self.stored_chunks.values());
+
+        voxels.fold((0, 0), |(count, bytes), voxels| (count + 1, bytes + voxels.memory_usage()))
+    }
+
+    //Opaque meshes inside the frustum, the 16 bit ones first so each index format is in
+    //one run for ChunkMeshArena::write_indirect_draws
+    pub fn get_opaque_meshes(&self, frustum: &camera::Frustum) -> (Vec<&mesh_arena::ChunkMesh>, ChunkDrawStats) {
+        let (visible_meshes, stats) = self.get_visible_meshes(frustum, false);
+
+        let mut meshes: Vec<&mesh_arena::ChunkMesh> = visible_meshes.into_iter().map(|(_, mesh)| mesh).collect();
+        meshes.sort_by_key(|mesh| mesh.get_index_format() == wgpu::IndexFormat::Uint32);
+
+        (meshes, stats)
+    }
+
+    //Meshes of the chunks inside the frustum, either the opaque or the translucent ones.
+    //Chunks with nothing to draw aren't counted as drawn or culled.
+    fn get_visible_meshes(
+        &self,
+        frustum: &camera::Frustum,
+        translucent: bool,
+    ) -> (Vec<(&Chunk, &mesh_arena::ChunkMesh)>, ChunkDrawStats) {
+        let mut stats = ChunkDrawStats::default();
+        let mut visible_meshes = Vec::new();
+
+        for chunk in self.chunks.values() {
+            let mesh = if translucent { &chunk.translucent_mesh } else { &chunk.mesh };
+            let mesh = match mesh {
+                Some(mesh) if mesh.get_index_count() > 0 => mesh,
+                _ => continue,
+            };
+
+            let (min, max) = chunk.bounds;
+            if !frustum.intersects_box(min, max) {
+                stats.culled += 1;
+                continue;
+            }
+
+            visible_meshes.push((chunk, mesh));
+            stats.drawn += 1;
+        }
+
+        (visible_meshes, stats)
+    }
+
+    //--------------------------------------------------
+
+    pub fn mark_chunk_dirty(&mut self, chunk_pos: glam::IVec3) {
+        if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
+            chunk.dirty = true;
+        }
+    }
+
+    pub fn mark_neighbours_dirty(&mut self, chunk_pos: glam::IVec3) {
+        for offset in ChunkNeighbours::offsets() {
+            self.mark_chunk_dirty(chunk_pos + offset);
+        }
+    }
+
+    //Marks the chunk containing the changed voxel as dirty, along with any
+    //neighbouring chunks whose faces or ambient occlusion the voxel touches.
+    pub fn mark_voxel_dirty(&mut self, chunk_pos: glam::IVec3, coord: voxel::LocalCoord) {
+        self.mark_chunk_dirty(chunk_pos);
+
+        for offset in ChunkNeighbours::offsets() {
+            //Stepping towards the neighbour leaves this chunk only from its border
+            let touches_neighbour = offset.to_array().iter().enumerate().all(|(axis, offset)| {
+                let mut step = glam::IVec3::ZERO;
+                step[axis] = *offset;
+                *offset == 0 || !coord.checked_offset(step).is_some_and(|next| self.chunk_size.contains(next))
+            });
+
+            if touches_neighbour {
+                self.mark_chunk_dirty(chunk_pos + offset);
+            }
         }
     }
 }
@@ -83,256 +880,863 @@ impl<'a> ChunkCollection {
 
 pub struct Chunk {
 
-    voxels: ChunkVoxels,
+    //Shared with any worker currently meshing this chunk or its neighbours
+    voxels: Arc<ChunkVoxels>,
 
     //voxels: std::collections::HashMap<u16, voxel::Voxel>,
     //pub voxel_count: u32,
 
     //pub instance_buffer: wgpu::Buffer,
 
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    index_count: u32,
+    mesh: Option<mesh_arena::ChunkMesh>,
+    //Faces of translucent voxels, drawn in a second pass
+    translucent_mesh: Option<mesh_arena::ChunkMesh>,
+    //World space box the chunk's mesh fits inside, used for frustum culling
+    bounds: (glam::Vec3, glam::Vec3),
+    //Level of detail the chunk is meshed at, from 0 (full resolution) up
+    lod: u8,
+    dirty: bool,
+    pending_mesh: Option<worker::JobTicket>,
+    //Voxels differ from what the generator made, so the chunk needs saving
+    edited: bool,
 
     //vertices: Vec<u16>,
 }
 
-impl<'a> Chunk {
-    pub fn new(device: &wgpu::Device, chunk_pos: glam::IVec3) -> Self {
-
-        let voxels = ChunkVoxels([[[
-            Some(voxel::Voxel::Grass); CHUNK_DEPTH as usize]; CHUNK_HEIGHT as usize]; CHUNK_WIDTH as usize
-        ]);
+impl Chunk {
+    pub fn new(chunk_pos: glam::IVec3, voxels: Arc<ChunkVoxels>, edited: bool) -> Self {
 
-        let (vertex_buffer, index_buffer, index_count) = Self::build_chunk_model(device, chunk_pos, &voxels);
+        let bounds = voxels.get_size().world_bounds(chunk_pos);
 
-        
+        //Mesh is built by the ChunkCollection once the surrounding chunks are known
         Self {
             voxels,
-            vertex_buffer,
-            index_buffer,
-            index_count,
+            mesh: None,
+            translucent_mesh: None,
+            bounds,
+            lod: 0,
+            dirty: true,
+            pending_mesh: None,
+            edited,
         }
         
     }
 
-    pub fn rebuild_chunk_model(
-        &mut self, 
-        device: &wgpu::Device,
-        chunk_pos: glam::IVec3,
-    ) {
-        let (
-            vertex_buffer, 
-            index_buffer, 
-            index_count
-        ) = Self::build_chunk_model(device, chunk_pos, &self.voxels);
+    //Voxel at a position relative to this chunk, looking into the neighbouring chunks
+    //when the position is outside of it.
+    fn get_voxel(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        pos: glam::IVec3,
+    ) -> Option<voxel::VoxelId> {
+        let (offset, coord) = voxels.get_size().wrap(pos);
 
-        self.vertex_buffer = vertex_buffer;
-        self.index_buffer = index_buffer;
-        self.index_count = index_count;
+        if offset == glam::IVec3::ZERO {
+            return voxels.get(coord);
+        }
+
+        neighbours.get(offset)?.get(coord)
     }
 
-    pub fn build_chunk_model(
-        device: &wgpu::Device,
-        chunk_pos: glam::IVec3,
+    fn is_opaque(
         voxels: &ChunkVoxels,
-    ) -> (wgpu::Buffer, wgpu::Buffer, u32) {
+        neighbours: &ChunkNeighbours,
+        registry: &registry::VoxelRegistry,
+        pos: glam::IVec3,
+    ) -> bool {
+        registry.is_opaque(Self::get_voxel(voxels, neighbours, pos))
+    }
 
-        let mut chunk_model = model::Model::default();
+    //Faces are hidden behind opaque voxels, and between translucent voxels of the same
+    //type so only the outside of a body of water is drawn
+    fn is_side_hidden(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        registry: &registry::VoxelRegistry,
+        coord: voxel::LocalCoord,
+        side: voxel::Side,
+    ) -> bool {
+        let voxel = voxels.get(coord);
+        let neighbour = match coord.checked_step(side).filter(|next| voxels.get_size().contains(*next)) {
+            Some(next) => voxels.get(next),
+            None => Self::get_voxel(voxels, neighbours, coord.as_ivec3() + side.get_normal()),
+        };
+
+        registry.is_opaque(neighbour) || (neighbour == voxel && registry.is_translucent(voxel))
+    }
 
-        //println!("Total size: ({}, {}, {})", voxels.0.len())
+    //Ambient occlusion level of each corner of a voxel face, in the same order as the
+    //vertices from voxel::Voxel::get_side. Goes from 0 (darkest) to 3 (unoccluded) and
+    //comes from the two voxels beside the corner and the one diagonal to it.
+    fn get_face_ao(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        registry: &registry::VoxelRegistry,
+        coord: voxel::LocalCoord,
+        side: voxel::Side,
+    ) -> [u8; 4] {
+        let normal = side.get_normal();
+        let front = coord.as_ivec3() + normal;
+
+        let mut ao = [3; 4];
+
+        for (index, corner) in voxel::Voxel::get_side_corners(side).iter().enumerate() {
+            let mut offsets = [glam::IVec3::ZERO; 2];
+            let mut offset_count = 0;
+
+            for axis in 0..3 {
+                if normal.to_array()[axis] != 0 {
+                    continue;
+                }
 
-        for x in 0..voxels.0.len() {
-            for y in 0..voxels.0[x].len() {
-                for z in 0..voxels.0[x][y].len() {
+                let mut offset = [0; 3];
+                offset[axis] = if corner[axis] == 0 { -1 } else { 1 };
+                offsets[offset_count] = glam::IVec3::from(offset);
+                offset_count += 1;
+            }
 
-                    if voxels.0[x][y][z].is_none() {
-                        continue;
-                    }
+            let side_1 = Self::is_opaque(voxels, neighbours, registry, front + offsets[0]);
+            let side_2 = Self::is_opaque(voxels, neighbours, registry, front + offsets[1]);
+            let corner = Self::is_opaque(voxels, neighbours, registry, front + offsets[0] + offsets[1]);
 
-                    let mut draw_left = false;
-                    let mut draw_right = false;
+            ao[index] = if side_1 && side_2 {
+                0
+            } else {
+                3 - (side_1 as u8 + side_2 as u8 + corner as u8)
+            };
+        }
 
-                    let mut draw_top = false;
-                    let mut draw_bottom = false;
+        ao
+    }
 
-                    let mut draw_front = false;
-                    let mut draw_back = false;
+    //Sets the occlusion of the face's corners (the shader darkens them) and splits the
+    //quad along the diagonal that keeps the shading symmetrical.
+    fn apply_face_ao(face: &mut model::Mesh, ao: [u8; 4]) {
+        for (vertex, ao) in face.vertices.iter_mut().zip(ao) {
+            vertex.set_ao(ao);
+        }
 
+        if ao[0] + ao[2] > ao[1] + ao[3] {
+            face.indices = Vec::from(voxel::Voxel::FLIPPED_INDICES);
+        }
+    }
 
-                    if x == 0 && x == CHUNK_WIDTH as usize - 1 {
-                        draw_left = true;
-                        draw_right = true;
-                    }
-                    else if x == 0 {
-                        draw_left = true;
+    pub fn build_chunk_mesh(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        meshing_mode: MeshingMode,
+        lod: u8,
+        registry: &registry::VoxelRegistry,
+    ) -> ChunkMeshData {
+
+        let chunk_size = voxels.get_size();
+
+        //Distant chunks are meshed from a lower resolution copy of the voxels
+        let scale = 1 << lod;
+        let downsampled;
+        let (voxels, neighbours) = if lod == 0 {
+            (voxels, neighbours)
+        } else {
+            downsampled = (voxels.downsample(scale), neighbours.downsample(scale));
+            (&downsampled.0, &downsampled.1)
+        };
+
+        //Vertices are relative to the chunk, its origin is given to the shader when drawing
+        let (mut opaque_model, mut translucent_model) = match meshing_mode {
+            MeshingMode::Naive => Self::build_naive_model(voxels, neighbours, registry),
+            MeshingMode::Greedy => Self::build_greedy_model(voxels, neighbours, registry),
+        };
+
+        if lod > 0 {
+            Self::upscale_model(&mut opaque_model, scale, chunk_size);
+            Self::upscale_model(&mut translucent_model, scale, chunk_size);
+        }
 
-                        if voxels.0[x + 1][y][z].is_none() {
-                            draw_right = true;
-                        }
-                    }
-                    else if x == CHUNK_WIDTH as usize - 1 {
-                        draw_right = true;
+        ChunkMeshData {
+            opaque: opaque_model.build_model(),
+            translucent: translucent_model.build_model(),
+        }
+    }
 
-                        if voxels.0[x - 1][y][z].is_none() {
-                            draw_left = true;
-                        }
-                    }
-                    else {
-                        if voxels.0[x + 1][y][z].is_none() {
-                            draw_right = true;
-                        }
-                        if voxels.0[x - 1][y][z].is_none() {
-                            draw_left = true;
-                        }
-                    }
+    //Translucent faces go in a separate model, returned as (opaque, translucent)
+    fn get_target_model<'m>(
+        models: &'m mut (model::Model, model::Model),
+        voxel: &voxel::Voxel,
+    ) -> &'m mut model::Model {
+        if voxel.is_translucent() {
+            &mut models.1
+        } else {
+            &mut models.0
+        }
+    }
 
-                    if y == 0 && y == CHUNK_HEIGHT as usize - 1 {
-                        draw_bottom = true;
-                        draw_top = true;
-                    }
-                    else if y == 0 {
-                        draw_bottom = true;
+    fn build_naive_model(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        registry: &registry::VoxelRegistry,
+    ) -> (model::Model, model::Model) {
 
-                        if voxels.0[x][y + 1][z].is_none() {
-                            draw_top = true;
-                        }
-                    }
-                    else if y == CHUNK_HEIGHT as usize - 1 {
-                        draw_top = true;
+        let mut models = <(model::Model, model::Model)>::default();
 
-                        if voxels.0[x][y - 1][z].is_none() {
-                            draw_bottom = true;
-                        }
-                    }
-                    else {
-                        if voxels.0[x][y + 1][z].is_none() {
-                            draw_top = true;
-                        }
-                        if voxels.0[x][y - 1][z].is_none() {
-                            draw_bottom = true;
-                        }
-                    }
+        for coord in voxels.get_size().coords() {
 
-                    if z == 0 && z == CHUNK_DEPTH as usize - 1 {
-                        draw_back = true;
-                        draw_front = true;
-                    }
-                    else if z == 0 {
-                        draw_back = true;
+            let voxel = match voxels.get(coord).and_then(|id| registry.get(id)) {
+                Some(voxel) => voxel,
+                None => continue,
+            };
 
-                        if voxels.0[x][y][z + 1].is_none() {
-                            draw_front = true;
-                        }
+            let mut voxel_model = model::Model::default();
+
+            for side in voxel::Side::ALL {
+                if !Self::is_side_hidden(voxels, neighbours, registry, coord, side) {
+                    let mut face = voxel::Voxel::get_side(side, voxel.id);
+                    Self::apply_face_ao(&mut face, Self::get_face_ao(voxels, neighbours, registry, coord, side));
+                    voxel_model.add_mesh(face);
+                }
+            }
+
+            voxel_model.move_model([coord.x(), coord.y(), coord.z()]);
+
+            Self::get_target_model(&mut models, voxel).add_model(voxel_model);
+        }
+
+        models
+    }
+
+    //Sweeps each side of the chunk one slice at a time, building a mask of the visible
+    //faces in that slice and merging runs of the same voxel into rectangles. Faces are
+    //only merged when their ambient occlusion matches too.
+    fn build_greedy_model(
+        voxels: &ChunkVoxels,
+        neighbours: &ChunkNeighbours,
+        registry: &registry::VoxelRegistry,
+    ) -> (model::Model, model::Model) {
+
+        //Voxel type and ambient occlusion of a visible face
+        type FaceKey = (voxel::VoxelId, [u8; 4]);
+
+        let mut models = <(model::Model, model::Model)>::default();
+
+        let chunk_size = voxels.get_size().to_array();
+
+        for side in voxel::Side::ALL {
+            let normal = side.get_normal();
+            let axis = if normal.x != 0 { 0 } else if normal.y != 0 { 1 } else { 2 };
+            let u_axis = (axis + 1) % 3;
+            let v_axis = (axis + 2) % 3;
+
+            let (size_u, size_v) = (chunk_size[u_axis], chunk_size[v_axis]);
+            let mut mask: Vec<Option<FaceKey>> = vec![None; size_u * size_v];
+
+            for d in 0..chunk_size[axis] {
+
+                //--------------------------------------------------
+
+                for v in 0..size_v {
+                    for u in 0..size_u {
+                        let mut pos = glam::IVec3::ZERO;
+                        pos[axis] = d as i32;
+                        pos[u_axis] = u as i32;
+                        pos[v_axis] = v as i32;
+
+                        mask[v * size_u + u] = voxels.get_size().local_coord(pos).and_then(|coord| {
+                            let voxel = registry.get(voxels.get(coord)?)?;
+                            if Self::is_side_hidden(voxels, neighbours, registry, coord, side) {
+                                return None;
+                            }
+
+                            Some((voxel.id, Self::get_face_ao(voxels, neighbours, registry, coord, side)))
+                        });
                     }
-                    else if z == CHUNK_DEPTH as usize - 1 {
-                        draw_front = true;
+                }
 
-                        if voxels.0[x][y][z - 1].is_none() {
-                            draw_back = true;
+                //--------------------------------------------------
+
+                for v in 0..size_v {
+                    let mut u = 0;
+                    while u < size_u {
+                        let face_key = match mask[v * size_u + u] {
+                            Some(face_key) => face_key,
+                            None => {
+                                u += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut width = 1;
+                        while u + width < size_u && mask[v * size_u + u + width] == Some(face_key) {
+                            width += 1;
                         }
-                    }
-                    else {
-                        if voxels.0[x][y][z + 1].is_none() {
-                            draw_front = true;
+
+                        let mut height = 1;
+                        'grow: while v + height < size_v {
+                            for du in 0..width {
+                                if mask[(v + height) * size_u + u + du] != Some(face_key) {
+                                    break 'grow;
+                                }
+                            }
+                            height += 1;
                         }
-                        if voxels.0[x][y][z - 1].is_none() {
-                            draw_back = true;
+
+                        for dv in 0..height {
+                            for du in 0..width {
+                                mask[(v + dv) * size_u + u + du] = None;
+                            }
                         }
-                    }
 
-                    let voxel_color = voxels.0[x][y][z].unwrap().get_color();
-                    let mut voxel_model = model::Model::default();
+                        let mut min = [0; 3];
+                        min[axis] = d;
+                        min[u_axis] = u;
+                        min[v_axis] = v;
 
-                    if draw_left    {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Left, voxel_color));}
-                    if draw_right   {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Right, voxel_color));}
-                    if draw_top     {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Top, voxel_color));}
-                    if draw_bottom  {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Bottom, voxel_color));}
-                    if draw_front   {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Front, voxel_color));}
-                    if draw_back    {voxel_model.add_mesh(voxel::Voxel::get_side(voxel::Side::Back, voxel_color));}
+                        let mut max = min;
+                        max[u_axis] = u + width - 1;
+                        max[v_axis] = v + height - 1;
 
-                    voxel_model.move_model([
-                        x as f32 * voxel::VOXEL_WIDTH,
-                        y as f32 * voxel::VOXEL_HEIGHT,
-                        z as f32 * voxel::VOXEL_DEPTH,
-                    ]);
+                        let (id, ao) = face_key;
+                        let mut face = voxel::Voxel::get_side(side, id);
+                        Self::apply_face_ao(&mut face, ao);
+                        Self::stretch_face(&mut face, min, max);
+
+                        if let Some(voxel) = registry.get(id) {
+                            Self::get_target_model(&mut models, voxel).add_mesh(face);
+                        }
 
-                    chunk_model.add_model(voxel_model);
+                        u += width;
+                    }
                 }
+
+                //--------------------------------------------------
             }
         }
 
-        chunk_model.move_model([
-            chunk_pos.x as f32 * voxel::VOXEL_WIDTH * CHUNK_WIDTH as f32,
-            chunk_pos.y as f32 * voxel::VOXEL_HEIGHT * CHUNK_HEIGHT as f32,
-            chunk_pos.z as f32 * voxel::VOXEL_DEPTH * CHUNK_DEPTH as f32,
-        ]);
-
-        let (vertices, indices) = chunk_model.build_model();
-
-
-        let vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Chunk Vertex Buffer"),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
-        );
+        models
+    }
 
-        let index_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Chunk Index Buffer"),
-                contents: bytemuck::cast_slice(&indices),
-                usage: wgpu::BufferUsages::INDEX,
+    //Turns vertex positions in downsampled voxels back into full size voxels. Cells at the
+    //far edges can be cut short by the chunk size, so their corners are clamped to it.
+    fn upscale_model(chunk_model: &mut model::Model, scale: u8, chunk_size: ChunkSize) {
+        let chunk_size = chunk_size.to_array();
+
+        for mesh in &mut chunk_model.meshes {
+            for vertex in &mut mesh.vertices {
+                let pos = vertex.get_pos();
+                vertex.set_pos([0, 1, 2].map(|axis| {
+                    (pos[axis] as usize * scale as usize).min(chunk_size[axis]) as u8
+                }));
             }
-        );
-
-        return (vertex_buffer, index_buffer, indices.len() as u32);
-
-        //build the new model here and put its vertices and indicies in a buffer
-        //and work it into the shader somehow. Also rework draw chunk trait :D
+        }
+    }
 
+    //Moves a single voxel face so it spans the voxels from min to max (inclusive).
+    //Corners on the negative side of an axis go to min and the rest go to max.
+    fn stretch_face(face: &mut model::Mesh, min: [usize; 3], max: [usize; 3]) {
+        for vertex in &mut face.vertices {
+            let pos = vertex.get_pos();
+            vertex.set_pos([0, 1, 2].map(|axis| {
+                let voxel_index = if pos[axis] == 0 { min[axis] } else { max[axis] };
+                pos[axis] + voxel_index as u8
+            }));
+        }
     }
 
 }
 
 //====================================================================
 
+//Mesh of a chunk, split by the pass that draws it
+pub struct ChunkMeshData {
+    pub opaque: (Vec<model::Vertex>, model::Indices),
+    pub translucent: (Vec<model::Vertex>, model::Indices),
+}
+
+//Number of chunks with a mesh that were drawn or skipped for being outside the frustum
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChunkDrawStats {
+    pub drawn: usize,
+    pub culled: usize,
+}
+
 pub trait DrawChunk<'a> {
-    fn draw_chunks(&mut self, chunks: &'a ChunkCollection);
-    fn draw_chunk(&mut self, chunk: &'a Chunk);
+    fn draw_chunks(
+        &mut self,
+        meshes: &[&'a mesh_arena::ChunkMesh],
+        mesh_arena: &'a mesh_arena::ChunkMeshArena,
+        indirect_batches: Option<&[mesh_arena::IndirectBatch]>,
+    );
+    fn draw_translucent_chunks(
+        &mut self,
+        chunks: &'a ChunkCollection,
+        mesh_arena: &'a mesh_arena::ChunkMeshArena,
+        frustum: &camera::Frustum,
+        camera_position: glam::Vec3,
+    ) -> ChunkDrawStats;
+    fn draw_chunk_mesh(&mut self, mesh: &'a mesh_arena::ChunkMesh);
 }
 
 impl<'a, 'b> DrawChunk<'b> for wgpu::RenderPass<'a> 
 where 
     'b: 'a
 {
+    //Takes the meshes from ChunkCollection::get_opaque_meshes. With the batches written
+    //for them by ChunkMeshArena::write_indirect_draws there's one multi_draw_indexed_indirect
+    //per index format, otherwise each chunk is its own draw_indexed. Both read the chunk's
+    //origin from the instance it's drawn as, so nothing is rebound between chunks.
     fn draw_chunks(
         &mut self, 
-        chunks: &'b ChunkCollection
+        meshes: &[&'b mesh_arena::ChunkMesh],
+        mesh_arena: &'b mesh_arena::ChunkMeshArena,
+        indirect_batches: Option<&[mesh_arena::IndirectBatch]>,
     ) {
+        //Every mesh is in the same buffers, the index buffer only needs binding again
+        //for each index format in use
+        self.set_vertex_buffer(0, mesh_arena.get_vertex_buffer().slice(..));
+        self.set_vertex_buffer(1, mesh_arena.get_instance_buffer().slice(..));
+
+        if let (Some(indirect_batches), Some(indirect_buffer)) = (indirect_batches, mesh_arena.get_indirect_buffer()) {
+            for batch in indirect_batches {
+                self.set_index_buffer(mesh_arena.get_index_buffer().slice(..), batch.index_format);
+                self.multi_draw_indexed_indirect(indirect_buffer, batch.offset, batch.count);
+            }
+            return;
+        }
+
+        let mut bound_format = None;
+
+        for mesh in meshes {
+            if bound_format != Some(mesh.get_index_format()) {
+                self.set_index_buffer(mesh_arena.get_index_buffer().slice(..), mesh.get_index_format());
+                bound_format = Some(mesh.get_index_format());
+            }
+
+            self.draw_chunk_mesh(mesh);
+        }
+    }
+
+    //Blended over the opaque chunks, so the furthest chunks have to go first. Faces inside
+    //a chunk aren't sorted. There are few enough translucent chunks that they are drawn one
+    //at a time, which keeps them in order across index formats.
+    fn draw_translucent_chunks(
+        &mut self,
+        chunks: &'b ChunkCollection,
+        mesh_arena: &'b mesh_arena::ChunkMeshArena,
+        frustum: &camera::Frustum,
+        camera_position: glam::Vec3,
+    ) -> ChunkDrawStats {
+
+        let (mut visible_meshes, stats) = chunks.get_visible_meshes(frustum, true);
 
-        //self.set_vertex_buffer(0, chunks.vertex_buffer.slice(..));
-        //self.set_index_buffer(chunks.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        let distance = |chunk: &Chunk| ((chunk.bounds.0 + chunk.bounds.1) / 2.).distance_squared(camera_position);
+        visible_meshes.sort_by(|(a, _), (b, _)| distance(b).total_cmp(&distance(a)));
 
-        for chunk in chunks.chunks.values() {
-            self.draw_chunk(chunk);
+        self.set_vertex_buffer(0, mesh_arena.get_vertex_buffer().slice(..));
+        self.set_vertex_buffer(1, mesh_arena.get_instance_buffer().slice(..));
+
+        //Has to keep to the sorted order, so the index buffer is bound again whenever the
+        //format changes
+        let mut bound_format = None;
+
+        for (_, mesh) in visible_meshes.iter() {
+            if bound_format != Some(mesh.get_index_format()) {
+                self.set_index_buffer(mesh_arena.get_index_buffer().slice(..), mesh.get_index_format());
+                bound_format = Some(mesh.get_index_format());
+            }
+
+            self.draw_chunk_mesh(mesh);
         }
+
+        stats
     }
 
-    fn draw_chunk(
+    fn draw_chunk_mesh(
         &mut self,
-        chunk: &'b Chunk,
+        mesh: &'b mesh_arena::ChunkMesh,
     ) {
         //println!("Drawing chunk with {} indices and {} voxels", indices.end, chunk.voxel_count);
         //self.set_vertex_buffer(1, chunk.instance_buffer.slice(..));
         //self.draw_indexed(indices, 0, 0..chunk.voxel_count);
 
-        self.set_vertex_buffer(0, chunk.vertex_buffer.slice(..));
-        self.set_index_buffer(chunk.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        //Expects the arena's buffers to be bound already, see draw_chunks
+
+        //Fully hidden chunks have nothing to draw
+        if mesh.get_index_count() == 0 {
+            return;
+        }
 
-        self.draw_indexed(0..chunk.index_count, 0, 0..1);
+        //Drawn as its own instance so the shader gets its ChunkInstance
+        self.draw_indexed(mesh.get_indices(), mesh.get_base_vertex(), mesh.get_instances());
     }
 }
 
-//====================================================================
\ No newline at end of file
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Terraced hills with a cave underneath, so there are large flat areas to merge as well
+    //as steps, overhangs and faces hidden inside the terrain
+    fn test_voxels(size: ChunkSize, voxel: Option<voxel::VoxelId>) -> ChunkVoxels {
+        let mut voxels = ChunkVoxels::empty(size);
+
+        for coord in size.coords() {
+            let (x, y, z) = (coord.x() as usize, coord.y() as usize, coord.z() as usize);
+            let height = 4 + (x / 4 + z / 3) % 5;
+            let cave = (5..9).contains(&x) && (1..3).contains(&y) && (5..9).contains(&z);
+
+            if y < height && !cave {
+                voxels.set(coord, voxel);
+            }
+        }
+
+        voxels
+    }
+
+    fn test_mesh(voxels: &ChunkVoxels, meshing_mode: MeshingMode, registry: &registry::VoxelRegistry) -> ChunkMeshData {
+        let neighbours = ChunkNeighbours::new(&std::collections::HashMap::new(), glam::IVec3::ZERO);
+        Chunk::build_chunk_mesh(voxels, &neighbours, meshing_mode, 0, registry)
+    }
+
+    //Twice the area covered by the triangles facing each way, indexed by Side
+    fn face_areas((vertices, indices): &(Vec<model::Vertex>, model::Indices)) -> [i32; 6] {
+        let indices: Vec<usize> = match indices {
+            model::Indices::U16(indices) => indices.iter().map(|index| *index as usize).collect(),
+            model::Indices::U32(indices) => indices.iter().map(|index| *index as usize).collect(),
+        };
+
+        let mut areas = [0; 6];
+
+        for triangle in indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| glam::IVec3::from(vertices[triangle[corner]].get_pos().map(|axis| axis as i32)));
+            let normal = (b - a).cross(c - a);
+
+            let side = match normal.to_array() {
+                [0, y, 0] if y > 0 => voxel::Side::Top,
+                [0, y, 0] if y < 0 => voxel::Side::Bottom,
+                [0, 0, z] if z > 0 => voxel::Side::Front,
+                [0, 0, z] if z < 0 => voxel::Side::Back,
+                [x, 0, 0] if x < 0 => voxel::Side::Left,
+                [x, 0, 0] if x > 0 => voxel::Side::Right,
+                _ => panic!("Triangle isn't axis aligned: {:?}", normal),
+            };
+
+            areas[side as usize] += normal.abs().max_element();
+        }
+
+        areas
+    }
+
+    //Xorshift, so the voxels written are random but the same every run
+    fn test_rng(mut state: u64) -> impl FnMut() -> usize {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        }
+    }
+
+    //Compares every voxel against a plain array in the same order as coords()
+    fn assert_voxels_match(voxels: &ChunkVoxels, expected: &[Option<voxel::VoxelId>]) {
+        for (coord, expected) in voxels.get_size().coords().zip(expected) {
+            assert_eq!(voxels.get(coord), *expected, "Voxel at {:?}", coord);
+        }
+    }
+
+    //Bytes taken by the packed indices alone
+    fn index_bytes(voxels: &ChunkVoxels) -> usize {
+        voxels.indices.len() * std::mem::size_of::<u64>()
+    }
+
+    #[test]
+    fn palette_storage_repacks_as_the_palette_grows() {
+        let size = DEFAULT_CHUNK_SIZE;
+        let coords: Vec<voxel::LocalCoord> = size.coords().collect();
+        let mut rng = test_rng(0x9e37_79b9_7f4a_7c15);
+
+        let mut voxels = ChunkVoxels::empty(size);
+        let mut expected = vec![None; size.volume()];
+
+        //Palette sizes that fill each index width, id 0 being empty space
+        for (palette_len, bits_per_index) in [(2, 1), (4, 2), (16, 4), (256, 8)] {
+            let write = |voxels: &mut ChunkVoxels, expected: &mut Vec<Option<voxel::VoxelId>>, i: usize, id: usize| {
+                let voxel = voxel::VoxelId::new(id as u8);
+                voxels.set(coords[i], voxel);
+                expected[i] = voxel;
+            };
+
+            //Every id at least once, then random ids over random voxels
+            for id in 0..palette_len {
+                write(&mut voxels, &mut expected, rng() % coords.len(), id);
+            }
+            for _ in 0..coords.len() {
+                write(&mut voxels, &mut expected, rng() % coords.len(), rng() % palette_len);
+            }
+
+            assert_eq!(voxels.palette.len(), palette_len);
+            assert_eq!(voxels.bits_per_index, bits_per_index);
+            assert_eq!(index_bytes(&voxels), size.volume().div_ceil(64 / bits_per_index as usize) * 8);
+            assert_voxels_match(&voxels, &expected);
+
+            //Anything below a byte per voxel beats the dense layout
+            if bits_per_index < 8 {
+                assert!(voxels.memory_usage() < ChunkVoxels::dense_memory_usage(size));
+            }
+        }
+    }
+
+    #[test]
+    fn compacting_shrinks_the_palette() {
+        let size = DEFAULT_CHUNK_SIZE;
+        let mut rng = test_rng(0x2545_f491_4f6c_dd1d);
+
+        let mut voxels = ChunkVoxels::empty(size);
+        let mut expected = vec![None; size.volume()];
+
+        for (i, coord) in size.coords().enumerate() {
+            let voxel = voxel::VoxelId::new((i % 256) as u8);
+            voxels.set(coord, voxel);
+            expected[i] = voxel;
+        }
+        assert_eq!(voxels.bits_per_index, 8);
+
+        //Overwrite everything with three ids, leaving the rest of the palette unused
+        for (i, coord) in size.coords().enumerate() {
+            let voxel = voxel::VoxelId::new((rng() % 3) as u8);
+            voxels.set(coord, voxel);
+            expected[i] = voxel;
+        }
+        assert_eq!(voxels.palette.len(), 256);
+
+        voxels.compact();
+        assert_eq!(voxels.palette.len(), 3);
+        assert_eq!(voxels.bits_per_index, 2);
+        assert_voxels_match(&voxels, &expected);
+
+        //Down to a single voxel type drops the indices altogether
+        let stone = voxel::VoxelId::new(3);
+        for coord in size.coords() {
+            voxels.set(coord, stone);
+        }
+        voxels.compact();
+        assert_eq!(voxels.palette, vec![stone]);
+        assert_eq!(voxels.bits_per_index, 0);
+        assert!(voxels.indices.is_empty());
+        assert_voxels_match(&voxels, &vec![stone; size.volume()]);
+    }
+
+    #[test]
+    fn single_voxel_chunks_store_no_indices() {
+        let size = DEFAULT_CHUNK_SIZE;
+        let stone = voxel::VoxelId::new(3);
+
+        let mut voxels = ChunkVoxels::filled(size, stone);
+        assert_voxels_match(&voxels, &vec![stone; size.volume()]);
+
+        //Setting the voxel it already holds stays on the fast path
+        voxels.set(voxel::LocalCoord::new(1, 2, 3).unwrap(), stone);
+        assert_eq!(voxels.bits_per_index, 0);
+        assert!(voxels.indices.is_empty());
+        assert!(voxels.memory_usage() * 10 < ChunkVoxels::dense_memory_usage(size));
+
+        //Any other voxel needs indices
+        voxels.set(voxel::LocalCoord::new(1, 2, 3).unwrap(), None);
+        assert_eq!(voxels.bits_per_index, 1);
+        assert_eq!(voxels.get(voxel::LocalCoord::new(1, 2, 3).unwrap()), None);
+        assert_eq!(voxels.get(voxel::LocalCoord::new(3, 2, 1).unwrap()), stone);
+    }
+
+    #[test]
+    fn edited_chunks_survive_unloading() {
+        let registry = Arc::new(registry::VoxelRegistry::default());
+        let generator = Box::new(terrain::HeightmapGenerator::new(1, &registry));
+        let mut chunks = ChunkCollection::new(DEFAULT_CHUNK_SIZE, MeshingMode::Greedy, generator, registry.clone());
+        chunks.load_radius = 1;
+        chunks.unload_radius = 2;
+        chunks.max_builds_per_frame = usize::MAX;
+
+        let edited_pos = glam::IVec3::new(1, 0, 0);
+        let generated_pos = glam::IVec3::new(-1, 0, 0);
+        let voxels = test_voxels(DEFAULT_CHUNK_SIZE, registry.get_id("stone"));
+
+        chunks.insert_chunk(edited_pos, Arc::new(voxels.clone()), true);
+        chunks.insert_chunk(generated_pos, Arc::new(ChunkVoxels::empty(DEFAULT_CHUNK_SIZE)), false);
+
+        let unloaded = chunks.unload_far_chunks(glam::IVec3::new(10, 0, 0));
+        assert_eq!(unloaded.len(), 2);
+        assert!(chunks.chunks.is_empty());
+
+        chunks.request_near_chunks(glam::IVec3::ZERO);
+
+        //Edited chunks come straight back, the rest are generated again
+        let chunk = &chunks.chunks[&edited_pos];
+        assert!(chunk.edited);
+        assert!(DEFAULT_CHUNK_SIZE.coords().all(|coord| chunk.voxels.get(coord) == voxels.get(coord)));
+        assert!(!chunks.pending_chunks.contains_key(&edited_pos));
+        assert!(!chunks.stored_chunks.contains_key(&edited_pos));
+
+        assert!(!chunks.chunks.contains_key(&generated_pos));
+        assert!(chunks.pending_chunks.contains_key(&generated_pos));
+    }
+
+    #[test]
+    fn stale_generation_results_are_discarded() {
+        let registry = Arc::new(registry::VoxelRegistry::default());
+        let generator = Box::new(terrain::HeightmapGenerator::new(1, &registry));
+        let mut chunks = ChunkCollection::new(DEFAULT_CHUNK_SIZE, MeshingMode::Greedy, generator, registry);
+        chunks.load_radius = 0;
+        chunks.unload_radius = 0;
+        chunks.max_builds_per_frame = usize::MAX;
+
+        let chunk_pos = glam::IVec3::ZERO;
+
+        chunks.request_near_chunks(glam::IVec3::ZERO);
+        let stale_ticket = chunks.pending_chunks[&chunk_pos];
+
+        //Unloading drops the pending job, loading again sends a new one
+        chunks.unload_far_chunks(glam::IVec3::new(10, 0, 0));
+        assert!(chunks.pending_chunks.is_empty());
+        chunks.request_near_chunks(glam::IVec3::ZERO);
+        let ticket = chunks.pending_chunks[&chunk_pos];
+        assert_ne!(ticket, stale_ticket);
+
+        let mut results = std::collections::HashMap::new();
+        let start = std::time::Instant::now();
+        while !(results.contains_key(&stale_ticket) && results.contains_key(&ticket)) {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "Chunk workers timed out");
+
+            for result in chunks.workers.try_iter() {
+                if let worker::ChunkJobResult::Generated { ticket, chunk_pos: result_pos, voxels } = result {
+                    if result_pos == chunk_pos {
+                        results.insert(ticket, voxels);
+                    }
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        chunks.receive_generated_chunk(stale_ticket, chunk_pos, *results.remove(&stale_ticket).unwrap());
+        assert!(!chunks.chunks.contains_key(&chunk_pos));
+        assert_eq!(chunks.pending_chunks.get(&chunk_pos), Some(&ticket));
+
+        chunks.receive_generated_chunk(ticket, chunk_pos, *results.remove(&ticket).unwrap());
+        assert!(chunks.chunks.contains_key(&chunk_pos));
+        assert!(!chunks.pending_chunks.contains_key(&chunk_pos));
+    }
+
+    #[test]
+    fn saving_keeps_chunks_already_saved() {
+        let dir = std::env::temp_dir().join(format!("cube_worlds_saving_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let registry = Arc::new(registry::VoxelRegistry::default());
+        let new_chunks = || {
+            let generator = Box::new(terrain::HeightmapGenerator::new(1, &registry));
+            ChunkCollection::new(DEFAULT_CHUNK_SIZE, MeshingMode::Greedy, generator, registry.clone())
+        };
+        let voxels = Arc::new(test_voxels(DEFAULT_CHUNK_SIZE, registry.get_id("stone")));
+
+        //Both chunks are in the same region but saved by different sessions
+        let mut chunks = new_chunks();
+        chunks.insert_chunk(glam::IVec3::new(0, 0, 0), voxels.clone(), true);
+        assert_eq!(chunks.save_to_dir(&dir).unwrap(), 1);
+
+        let mut chunks = new_chunks();
+        chunks.insert_chunk(glam::IVec3::new(1, 0, 0), voxels.clone(), true);
+        assert_eq!(chunks.save_to_dir(&dir).unwrap(), 2);
+
+        let mut chunks = new_chunks();
+        assert_eq!(chunks.load_from_dir(&dir).unwrap(), 2);
+        assert!(chunks.stored_chunks.contains_key(&glam::IVec3::new(0, 0, 0)));
+        assert!(chunks.stored_chunks.contains_key(&glam::IVec3::new(1, 0, 0)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_meshes_switch_to_32_bit_indices() {
+        let registry = registry::VoxelRegistry::default();
+        let chunk_size = ChunkSize::new(32, 32, 32).unwrap();
+
+        //No two voxels touch, so every voxel keeps all six faces
+        let mut voxels = ChunkVoxels::empty(chunk_size);
+        for coord in chunk_size.coords().filter(|coord| (coord.x() + coord.y() + coord.z()) % 2 == 0) {
+            voxels.set(coord, registry.get_id("stone"));
+        }
+
+        let (vertices, indices) = test_mesh(&voxels, MeshingMode::Naive, &registry).opaque;
+        assert_eq!(vertices.len(), chunk_size.volume() / 2 * 6 * 4);
+
+        let indices = match indices {
+            model::Indices::U32(indices) => indices,
+            model::Indices::U16(_) => panic!("{} vertices can't use 16 bit indices", vertices.len()),
+        };
+
+        //Each face's indices point at its own four vertices, past the 16 bit range as well
+        assert_eq!(indices.len(), vertices.len() / 4 * 6);
+        for (face, face_indices) in indices.chunks(6).enumerate() {
+            assert!(
+                face_indices.iter().all(|index| (*index as usize) / 4 == face),
+                "Face {} has indices {:?}",
+                face,
+                face_indices,
+            );
+        }
+        assert_eq!(indices.iter().max().copied(), Some(vertices.len() as u32 - 1));
+
+        let small_voxels = test_voxels(DEFAULT_CHUNK_SIZE, registry.get_id("stone"));
+        let (_, small_indices) = test_mesh(&small_voxels, MeshingMode::Naive, &registry).opaque;
+        assert!(matches!(small_indices, model::Indices::U16(_)));
+    }
+
+    #[test]
+    fn lod_levels_fit_inside_the_load_radius() {
+        for load_radius in [2, 5, CHUNK_LOAD_RADIUS, 32] {
+            let lods: Vec<u8> = (0..=load_radius as i32)
+                .map(|distance| ChunkCollection::lod_for_distance(load_radius, 0, distance))
+                .collect();
+
+            assert_eq!(lods[0], 0, "Load radius {}", load_radius);
+            assert_eq!(lods[load_radius as usize], 3, "Load radius {}", load_radius);
+            assert!(lods.windows(2).all(|pair| pair[0] <= pair[1]), "Load radius {}", load_radius);
+        }
+    }
+
+    #[test]
+    fn lod_changes_need_to_pass_the_hysteresis_band() {
+        let load_radius = 16;
+        //Level 1 starts 6 chunks out
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 0, 5), 0);
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 0, 6), 1);
+
+        //Coming back in, the chunk stays at level 1 for one more chunk
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 1, 6), 1);
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 1, 5), 1);
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 1, 4), 0);
+
+        //New chunks go straight to their level
+        assert_eq!(ChunkCollection::lod_for_distance(load_radius, 0, load_radius as i32), 3);
+    }
+
+    #[test]
+    fn greedy_mesh_covers_the_naive_surface() {
+        let registry = registry::VoxelRegistry::default();
+
+        //Grass has colour variation, which mustn't stop its faces merging
+        for name in ["stone", "grass"] {
+            let voxels = test_voxels(ChunkSize::new(16, 16, 16).unwrap(), registry.get_id(name));
+
+            let naive = test_mesh(&voxels, MeshingMode::Naive, &registry).opaque;
+            let greedy = test_mesh(&voxels, MeshingMode::Greedy, &registry).opaque;
+
+            assert_eq!(face_areas(&naive), face_areas(&greedy), "{}", name);
+            assert!(
+                greedy.1.len() * 3 < naive.1.len(),
+                "Greedy {} mesh has {} indices, naive has {}",
+                name,
+                greedy.1.len(),
+                naive.1.len(),
+            );
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//====================================================================
+
+use std::{borrow::Cow, ops::Range};
+
+use super::model;
+
+//====================================================================
+
+//Starting sizes of the shared buffers, each doubles whenever it runs out of space
+const INITIAL_VERTEX_CAPACITY: u64 = 1 << 16; //In vertices
+const INITIAL_INDEX_CAPACITY: u64 = 1 << 20; //In bytes
+const INITIAL_INSTANCE_CAPACITY: u64 = 1 << 10; //In chunks
+const INITIAL_INDIRECT_CAPACITY: u64 = 1 << 10; //In draws
+
+//Index ranges start on a 4 byte boundary so both index formats line up and every upload
+//is a multiple of wgpu::COPY_BUFFER_ALIGNMENT
+const INDEX_ALIGNMENT: u64 = wgpu::COPY_BUFFER_ALIGNMENT;
+
+const VERTEX_SIZE: u64 = std::mem::size_of::<model::Vertex>() as u64;
+const INSTANCE_SIZE: u64 = std::mem::size_of::<ChunkInstance>() as u64;
+const INDIRECT_SIZE: u64 = std::mem::size_of::<DrawIndexedIndirect>() as u64;
+
+//Drawing all the opaque chunks with one multi_draw_indexed_indirect per index format needs
+//both of these. Without them each chunk is drawn on its own.
+pub const MULTI_DRAW_FEATURES: wgpu::Features =
+    wgpu::Features::MULTI_DRAW_INDIRECT.union(wgpu::Features::INDIRECT_FIRST_INSTANCE);
+
+//====================================================================
+
+//Hands out ranges of a fixed amount of space, first fit. Freed ranges are merged with
+//any free space either side of them so they can be reused by larger allocations.
+pub struct RangeAllocator {
+    capacity: u64,
+    //Sorted by start, and never touching each other
+    free_ranges: Vec<Range<u64>>,
+}
+
+impl RangeAllocator {
+    pub fn new(capacity: u64) -> Self {
+        let mut allocator = Self {
+            capacity: 0,
+            free_ranges: Vec::new(),
+        };
+        allocator.grow(capacity);
+
+        allocator
+    }
+
+    pub fn allocate(&mut self, size: u64) -> Option<Range<u64>> {
+        let index = self.free_ranges.iter().position(|range| range.end - range.start >= size)?;
+
+        let free_range = &mut self.free_ranges[index];
+        let allocation = free_range.start..free_range.start + size;
+
+        free_range.start += size;
+        if free_range.is_empty() {
+            self.free_ranges.remove(index);
+        }
+
+        Some(allocation)
+    }
+
+    pub fn free(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let index = self.free_ranges.partition_point(|free_range| free_range.start < range.start);
+
+        let joins_previous = index > 0 && self.free_ranges[index - 1].end == range.start;
+        let joins_next = self.free_ranges.get(index).is_some_and(|next| next.start == range.end);
+
+        match (joins_previous, joins_next) {
+            (true, true) => {
+                let next = self.free_ranges.remove(index);
+                self.free_ranges[index - 1].end = next.end;
+            }
+            (true, false) => self.free_ranges[index - 1].end = range.end,
+            (false, true) => self.free_ranges[index].start = range.start,
+            (false, false) => self.free_ranges.insert(index, range),
+        }
+    }
+
+    //New space is added to the end, joining the last free range if it reaches the end
+    pub fn grow(&mut self, new_capacity: u64) {
+        let old_capacity = self.capacity;
+        self.capacity = new_capacity;
+        self.free(old_capacity..new_capacity);
+    }
+
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn get_stats(&self) -> AllocatorStats {
+        let free: u64 = self.free_ranges.iter().map(|range| range.end - range.start).sum();
+
+        AllocatorStats {
+            capacity: self.capacity,
+            used: self.capacity - free,
+            free_ranges: self.free_ranges.len(),
+            largest_free: self.free_ranges.iter().map(|range| range.end - range.start).max().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocatorStats {
+    pub capacity: u64,
+    pub used: u64,
+    pub free_ranges: usize,
+    pub largest_free: u64,
+}
+
+impl AllocatorStats {
+    //0 while all the free space is in one piece, getting closer to 1 as it's split up
+    //into gaps too small for anything but the smallest meshes
+    pub fn fragmentation(&self) -> f32 {
+        let free = self.capacity - self.used;
+        if free == 0 {
+            return 0.;
+        }
+
+        1. - self.largest_free as f32 / free as f32
+    }
+
+    fn scaled(self, scale: u64) -> Self {
+        Self {
+            capacity: self.capacity * scale,
+            used: self.used * scale,
+            free_ranges: self.free_ranges,
+            largest_free: self.largest_free * scale,
+        }
+    }
+}
+
+//====================================================================
+
+//Position of a chunk for the shader. Vertices are in voxels from the chunk's lowest corner,
+//so this is where that corner is and how big a voxel is. Read as a per instance vertex
+//attribute, each chunk being drawn as the instance its ChunkInstance is stored at.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ChunkInstance {
+    origin: [f32; 3],
+    voxel_size: [f32; 3],
+}
+impl ChunkInstance {
+    pub fn new(origin: glam::Vec3, voxel_size: glam::Vec3) -> Self {
+        Self {
+            origin: origin.to_array(),
+            voxel_size: voxel_size.to_array(),
+        }
+    }
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: INSTANCE_SIZE as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                //Origin
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                //Voxel size
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
+}
+
+//Arguments of one draw in the indirect buffer, in the order multi_draw_indexed_indirect
+//reads them
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+//Draws next to each other in the indirect buffer that share an index format, so they can
+//be made with one call
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndirectBatch {
+    pub index_format: wgpu::IndexFormat,
+    //In bytes
+    pub offset: u64,
+    pub count: u32,
+}
+
+//====================================================================
+
+//Where a chunk's mesh lives in the arena. Has to be given back with ChunkMeshArena::free
+//once the chunk is unloaded or remeshed, otherwise its space is never reused.
+pub struct ChunkMesh {
+    //In vertices, the start is used as the base vertex when drawing
+    vertex_range: Range<u64>,
+    //In bytes
+    index_range: Range<u64>,
+    index_count: u32,
+    index_format: wgpu::IndexFormat,
+    //Slot of the chunk's ChunkInstance, which is also the instance it's drawn as
+    instance_slot: Range<u64>,
+}
+
+impl ChunkMesh {
+    pub fn get_base_vertex(&self) -> i32 {
+        self.vertex_range.start as i32
+    }
+
+    //Indices to draw, counted from the start of the index buffer in this mesh's format
+    pub fn get_indices(&self) -> Range<u32> {
+        let index_size = match self.index_format {
+            wgpu::IndexFormat::Uint16 => 2,
+            wgpu::IndexFormat::Uint32 => 4,
+        };
+
+        let first_index = (self.index_range.start / index_size) as u32;
+        first_index..first_index + self.index_count
+    }
+
+    pub fn get_index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    pub fn get_index_format(&self) -> wgpu::IndexFormat {
+        self.index_format
+    }
+
+    pub fn get_instances(&self) -> Range<u32> {
+        self.instance_slot.start as u32..self.instance_slot.end as u32
+    }
+}
+
+//====================================================================
+
+//Every chunk mesh is kept in one shared vertex buffer and one shared index buffer, so
+//remeshing a chunk doesn't allocate GPU memory and drawing only binds the buffers once.
+//Indices are relative to each mesh's first vertex and drawn with a base vertex.
+//Each chunk's ChunkInstance is kept in a shared instance buffer the same way and picked
+//by the instance the chunk is drawn as, so nothing has to be rebound between chunks.
+pub struct ChunkMeshArena {
+    vertex_buffer: wgpu::Buffer,
+    vertex_allocator: RangeAllocator,
+
+    index_buffer: wgpu::Buffer,
+    index_allocator: RangeAllocator,
+
+    instance_buffer: wgpu::Buffer,
+    instance_allocator: RangeAllocator,
+
+    //Only made if the device has MULTI_DRAW_FEATURES. Written again every frame, so it's
+    //replaced rather than copied when it grows.
+    indirect_buffer: Option<wgpu::Buffer>,
+    indirect_capacity: u64, //In draws
+}
+
+impl ChunkMeshArena {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let indirect_buffer = device
+            .features()
+            .contains(MULTI_DRAW_FEATURES)
+            .then(|| Self::create_indirect_buffer(device, INITIAL_INDIRECT_CAPACITY));
+
+        Self {
+            vertex_buffer: Self::create_vertex_buffer(device, INITIAL_VERTEX_CAPACITY),
+            vertex_allocator: RangeAllocator::new(INITIAL_VERTEX_CAPACITY),
+
+            index_buffer: Self::create_index_buffer(device, INITIAL_INDEX_CAPACITY),
+            index_allocator: RangeAllocator::new(INITIAL_INDEX_CAPACITY),
+
+            instance_buffer: Self::create_instance_buffer(device, INITIAL_INSTANCE_CAPACITY),
+            instance_allocator: RangeAllocator::new(INITIAL_INSTANCE_CAPACITY),
+
+            indirect_buffer,
+            indirect_capacity: INITIAL_INDIRECT_CAPACITY,
+        }
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk Vertex Arena"),
+            size: capacity * VERTEX_SIZE,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_index_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk Index Arena"),
+            size: capacity,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk Instance Arena"),
+            size: capacity * INSTANCE_SIZE,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_indirect_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk Indirect Draws"),
+            size: capacity * INDIRECT_SIZE,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    //--------------------------------------------------
+
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &[model::Vertex],
+        indices: &model::Indices,
+        instance: ChunkInstance,
+    ) -> ChunkMesh {
+        //Fully hidden chunks don't take up any space
+        if vertices.is_empty() || indices.len() == 0 {
+            return ChunkMesh {
+                vertex_range: 0..0,
+                index_range: 0..0,
+                index_count: 0,
+                index_format: indices.format(),
+                instance_slot: 0..0,
+            };
+        }
+
+        let vertex_range = self.allocate_vertices(device, queue, vertices.len() as u64);
+
+        //16 bit meshes with an odd number of indices are padded out to the alignment
+        let mut index_bytes = Cow::Borrowed(indices.as_bytes());
+        let padded_len = (index_bytes.len() as u64).div_ceil(INDEX_ALIGNMENT) * INDEX_ALIGNMENT;
+        if padded_len as usize != index_bytes.len() {
+            index_bytes.to_mut().resize(padded_len as usize, 0);
+        }
+
+        let index_range = self.allocate_indices(device, queue, index_bytes.len() as u64);
+
+        queue.write_buffer(&self.vertex_buffer, vertex_range.start * VERTEX_SIZE, bytemuck::cast_slice(vertices));
+        queue.write_buffer(&self.index_buffer, index_range.start, &index_bytes);
+
+        let instance_slot = self.allocate_instance(device, queue);
+        queue.write_buffer(&self.instance_buffer, instance_slot.start * INSTANCE_SIZE, bytemuck::cast_slice(&[instance]));
+
+        ChunkMesh {
+            vertex_range,
+            index_range,
+            index_count: indices.len() as u32,
+            index_format: indices.format(),
+            instance_slot,
+        }
+    }
+
+    pub fn free(&mut self, mesh: ChunkMesh) {
+        self.vertex_allocator.free(mesh.vertex_range);
+        self.index_allocator.free(mesh.index_range);
+        self.instance_allocator.free(mesh.instance_slot);
+    }
+
+    fn allocate_vertices(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, count: u64) -> Range<u64> {
+        if let Some(range) = self.vertex_allocator.allocate(count) {
+            return range;
+        }
+
+        let old_capacity = self.vertex_allocator.get_capacity();
+        let new_capacity = (old_capacity * 2).max(old_capacity + count);
+
+        let new_buffer = Self::create_vertex_buffer(device, new_capacity);
+        Self::copy_buffer(device, queue, &self.vertex_buffer, &new_buffer, old_capacity * VERTEX_SIZE);
+
+        self.vertex_buffer = new_buffer;
+        self.vertex_allocator.grow(new_capacity);
+
+        self.vertex_allocator.allocate(count).expect("Vertex arena still full after growing")
+    }
+
+    fn allocate_indices(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, size: u64) -> Range<u64> {
+        if let Some(range) = self.index_allocator.allocate(size) {
+            return range;
+        }
+
+        let old_capacity = self.index_allocator.get_capacity();
+        let new_capacity = (old_capacity * 2).max(old_capacity + size);
+
+        let new_buffer = Self::create_index_buffer(device, new_capacity);
+        Self::copy_buffer(device, queue, &self.index_buffer, &new_buffer, old_capacity);
+
+        self.index_buffer = new_buffer;
+        self.index_allocator.grow(new_capacity);
+
+        self.index_allocator.allocate(size).expect("Index arena still full after growing")
+    }
+
+    fn allocate_instance(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Range<u64> {
+        if let Some(slot) = self.instance_allocator.allocate(1) {
+            return slot;
+        }
+
+        let old_capacity = self.instance_allocator.get_capacity();
+        let new_capacity = old_capacity * 2;
+
+        let new_buffer = Self::create_instance_buffer(device, new_capacity);
+        Self::copy_buffer(device, queue, &self.instance_buffer, &new_buffer, old_capacity * INSTANCE_SIZE);
+
+        self.instance_buffer = new_buffer;
+        self.instance_allocator.grow(new_capacity);
+
+        self.instance_allocator.allocate(1).expect("Instance arena still full after growing")
+    }
+
+    //Meshes already in the arena keep their ranges, so they're copied over as they are
+    fn copy_buffer(device: &wgpu::Device, queue: &wgpu::Queue, from: &wgpu::Buffer, to: &wgpu::Buffer, size: u64) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Chunk Arena Grow Encoder"),
+        });
+
+        encoder.copy_buffer_to_buffer(from, 0, to, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    //--------------------------------------------------
+
+    //Writes a draw for each mesh to the indirect buffer, in the order given. Meshes next to
+    //each other with the same index format end up in one batch, so they should be grouped
+    //by format. None if the device can't multi-draw and the meshes have to be drawn one
+    //at a time instead.
+    pub fn write_indirect_draws(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        meshes: &[&ChunkMesh],
+    ) -> Option<Vec<IndirectBatch>> {
+        self.indirect_buffer.as_ref()?;
+
+        let (draws, batches) = Self::build_indirect_draws(meshes);
+
+        if draws.len() as u64 > self.indirect_capacity {
+            self.indirect_capacity = (draws.len() as u64).next_power_of_two();
+            self.indirect_buffer = Some(Self::create_indirect_buffer(device, self.indirect_capacity));
+        }
+
+        if let (Some(indirect_buffer), false) = (&self.indirect_buffer, draws.is_empty()) {
+            queue.write_buffer(indirect_buffer, 0, bytemuck::cast_slice(&draws));
+        }
+
+        Some(batches)
+    }
+
+    fn build_indirect_draws(meshes: &[&ChunkMesh]) -> (Vec<DrawIndexedIndirect>, Vec<IndirectBatch>) {
+        let mut draws = Vec::with_capacity(meshes.len());
+        let mut batches: Vec<IndirectBatch> = Vec::new();
+
+        for mesh in meshes.iter().filter(|mesh| mesh.index_count > 0) {
+            match batches.last_mut() {
+                Some(batch) if batch.index_format == mesh.index_format => batch.count += 1,
+                _ => batches.push(IndirectBatch {
+                    index_format: mesh.index_format,
+                    offset: draws.len() as u64 * INDIRECT_SIZE,
+                    count: 1,
+                }),
+            }
+
+            draws.push(DrawIndexedIndirect {
+                index_count: mesh.index_count,
+                instance_count: 1,
+                first_index: mesh.get_indices().start,
+                base_vertex: mesh.get_base_vertex(),
+                first_instance: mesh.instance_slot.start as u32,
+            });
+        }
+
+        (draws, batches)
+    }
+
+    //--------------------------------------------------
+
+    pub fn get_vertex_buffer(&self) -> &wgpu::Buffer {
+        &self.vertex_buffer
+    }
+
+    pub fn get_index_buffer(&self) -> &wgpu::Buffer {
+        &self.index_buffer
+    }
+
+    pub fn get_instance_buffer(&self) -> &wgpu::Buffer {
+        &self.instance_buffer
+    }
+
+    pub fn get_indirect_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.indirect_buffer.as_ref()
+    }
+
+    //Usage of the vertex and index buffers, both in bytes
+    pub fn get_stats(&self) -> (AllocatorStats, AllocatorStats) {
+        (
+            self.vertex_allocator.get_stats().scaled(VERTEX_SIZE),
+            self.index_allocator.get_stats(),
+        )
+    }
+}
+
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_mesh(first_vertex: u64, first_index_byte: u64, index_count: u32, index_format: wgpu::IndexFormat, slot: u64) -> ChunkMesh {
+        ChunkMesh {
+            vertex_range: first_vertex..first_vertex + 4,
+            index_range: first_index_byte..first_index_byte + 12,
+            index_count,
+            index_format,
+            instance_slot: slot..slot + 1,
+        }
+    }
+
+    #[test]
+    fn allocations_take_the_first_range_that_fits() {
+        let mut allocator = RangeAllocator::new(100);
+
+        assert_eq!(allocator.allocate(10), Some(0..10));
+        assert_eq!(allocator.allocate(20), Some(10..30));
+        assert_eq!(allocator.allocate(30), Some(30..60));
+        assert_eq!(allocator.allocate(40), Some(60..100));
+        assert_eq!(allocator.allocate(1), None);
+
+        //Gaps of 10 at the start and 30 in the middle
+        allocator.free(0..10);
+        allocator.free(30..60);
+
+        assert_eq!(allocator.allocate(25), Some(30..55));
+        assert_eq!(allocator.allocate(5), Some(0..5));
+        //Two gaps of 5 are left, neither big enough on its own
+        assert_eq!(allocator.allocate(10), None);
+        assert_eq!(allocator.allocate(5), Some(5..10));
+        assert_eq!(allocator.allocate(5), Some(55..60));
+    }
+
+    #[test]
+    fn freed_ranges_merge_with_their_neighbours() {
+        let mut allocator = RangeAllocator::new(40);
+        let ranges: Vec<Range<u64>> = (0..4).map(|_| allocator.allocate(10).unwrap()).collect();
+        assert_eq!(allocator.get_stats().free_ranges, 0);
+
+        allocator.free(ranges[0].clone());
+        allocator.free(ranges[2].clone());
+        assert_eq!(allocator.free_ranges, vec![0..10, 20..30]);
+
+        //Joins the free space on both sides into one range
+        allocator.free(ranges[1].clone());
+        assert_eq!(allocator.free_ranges, vec![0..30]);
+
+        allocator.free(ranges[3].clone());
+        assert_eq!(allocator.free_ranges, vec![0..40]);
+        assert_eq!(allocator.allocate(40), Some(0..40));
+    }
+
+    #[test]
+    fn growing_joins_free_space_at_the_end() {
+        let mut allocator = RangeAllocator::new(30);
+        let first = allocator.allocate(10).unwrap();
+        allocator.allocate(10).unwrap();
+
+        allocator.grow(60);
+        assert_eq!(allocator.get_capacity(), 60);
+        assert_eq!(allocator.free_ranges, vec![20..60]);
+
+        //Space before the end stays separate
+        allocator.free(first);
+        allocator.grow(100);
+        assert_eq!(allocator.free_ranges, vec![0..10, 20..100]);
+
+        //Growing a full allocator adds a new range
+        let mut full = RangeAllocator::new(10);
+        full.allocate(10).unwrap();
+        full.grow(20);
+        assert_eq!(full.free_ranges, vec![10..20]);
+    }
+
+    #[test]
+    fn fragmentation_measures_how_split_the_free_space_is() {
+        let mut allocator = RangeAllocator::new(100);
+        assert_eq!(allocator.get_stats().fragmentation(), 0.);
+
+        let ranges: Vec<Range<u64>> = (0..10).map(|_| allocator.allocate(10).unwrap()).collect();
+        assert_eq!(allocator.get_stats().fragmentation(), 0.);
+
+        //40 free in four gaps of 10, so the largest is a quarter of the free space
+        for range in ranges.iter().step_by(2).take(4) {
+            allocator.free(range.clone());
+        }
+
+        let stats = allocator.get_stats();
+        assert_eq!(stats.used, 60);
+        assert_eq!(stats.free_ranges, 4);
+        assert_eq!(stats.largest_free, 10);
+        assert!((stats.fragmentation() - 0.75).abs() < 1e-6);
+
+        //Scaling to bytes doesn't change the ratio
+        assert!((stats.scaled(VERTEX_SIZE).fragmentation() - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn indirect_draws_are_batched_by_index_format() {
+        let meshes = [
+            test_mesh(0, 0, 6, wgpu::IndexFormat::Uint16, 3),
+            test_mesh(4, 12, 0, wgpu::IndexFormat::Uint16, 0),
+            test_mesh(8, 24, 12, wgpu::IndexFormat::Uint16, 1),
+            test_mesh(12, 40, 3, wgpu::IndexFormat::Uint32, 2),
+        ];
+        let meshes: Vec<&ChunkMesh> = meshes.iter().collect();
+
+        let (draws, batches) = ChunkMeshArena::build_indirect_draws(&meshes);
+
+        //The empty mesh is skipped
+        assert_eq!(
+            draws,
+            vec![
+                DrawIndexedIndirect { index_count: 6, instance_count: 1, first_index: 0, base_vertex: 0, first_instance: 3 },
+                DrawIndexedIndirect { index_count: 12, instance_count: 1, first_index: 12, base_vertex: 8, first_instance: 1 },
+                DrawIndexedIndirect { index_count: 3, instance_count: 1, first_index: 10, base_vertex: 12, first_instance: 2 },
+            ]
+        );
+        assert_eq!(
+            batches,
+            vec![
+                IndirectBatch { index_format: wgpu::IndexFormat::Uint16, offset: 0, count: 2 },
+                IndirectBatch { index_format: wgpu::IndexFormat::Uint32, offset: 2 * INDIRECT_SIZE, count: 1 },
+            ]
+        );
+    }
+}
@@ -1,3 +1,10 @@
 pub mod chunk;
 pub mod voxel;
-pub mod model;
\ No newline at end of file
+pub mod mesh_arena;
+pub mod model;
+pub mod noise;
+pub mod raycast;
+pub mod region;
+pub mod registry;
+pub mod terrain;
+pub mod worker;
\ No newline at end of file
@@ -1,5 +1,7 @@
 //====================================================================
 
+use super::voxel;
+
 //====================================================================
 
 #[derive(Default)]
@@ -7,7 +9,7 @@ pub struct Model {
     pub meshes: Vec<Mesh>,
 }
 impl Model {
-    pub fn move_model(&mut self, translation: [f32; 3]) {
+    pub fn move_model(&mut self, translation: [u8; 3]) {
 
         for mesh in &mut self.meshes {
             mesh.move_mesh(translation);
@@ -25,9 +27,12 @@ impl Model {
         }
     }
 
-    pub fn build_model(&mut self) -> (Vec<Vertex>, Vec<u16>) {
+    //Indices are 16 bit unless there are too many vertices for them to address
+    pub fn build_model(&mut self) -> (Vec<Vertex>, Indices) {
+
+        let vertex_count: usize = self.meshes.iter().map(|mesh| mesh.vertices.len()).sum();
 
-        let mut vertices = Vec::new();
+        let mut vertices = Vec::with_capacity(vertex_count);
         let mut indices = Vec::new();
 
         let mut current_index = 0;
@@ -35,16 +40,22 @@ impl Model {
         for mesh in self.meshes.iter() {
 
             for vertex in mesh.vertices.iter() {
-                vertices.push(vertex.clone());
+                vertices.push(*vertex);
             }
 
             for index in mesh.indices.iter() {
-                indices.push(index + current_index);
+                indices.push(*index as u32 + current_index);
             }
 
-            current_index += mesh.vertices.len() as u16;
+            current_index += mesh.vertices.len() as u32;
         }
 
+        let indices = if vertex_count > u16::MAX as usize {
+            Indices::U32(indices)
+        } else {
+            Indices::U16(indices.into_iter().map(|index| index as u16).collect())
+        };
+
         //println!("Model built:");
         //println!("  Vertices: {:#?}", vertices);
         //println!("  Indices: {:?}", indices);
@@ -60,45 +71,154 @@ pub struct Mesh {
     pub indices: Vec<u16>,
 }
 impl Mesh {
-    pub fn move_mesh(&mut self, translation: [f32; 3]) {
+    pub fn move_mesh(&mut self, translation: [u8; 3]) {
         for vertex in &mut self.vertices {
-            vertex.pos[0] += translation[0];
-            vertex.pos[1] += translation[1];
-            vertex.pos[2] += translation[2];
+            let pos = vertex.get_pos();
+            vertex.set_pos([
+                pos[0] + translation[0],
+                pos[1] + translation[1],
+                pos[2] + translation[2],
+            ]);
         }
     }
 }
 
 //====================================================================
 
+pub enum Indices {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl Indices {
+    pub fn len(&self) -> usize {
+        match self {
+            Indices::U16(indices) => indices.len(),
+            Indices::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn format(&self) -> wgpu::IndexFormat {
+        match self {
+            Indices::U16(_) => wgpu::IndexFormat::Uint16,
+            Indices::U32(_) => wgpu::IndexFormat::Uint32,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Indices::U16(indices) => bytemuck::cast_slice(indices),
+            Indices::U32(indices) => bytemuck::cast_slice(indices),
+        }
+    }
+}
+
+//====================================================================
+
+//Chunk vertex packed into a single u32, decoded again in shader.wgsl
+//
+//  0 iiiiiiii aa sss zzzzzz yyyyyy xxxxxx
+//
+//x, y and z are the corner's position in voxels from the chunk's origin, which is passed
+//to the shader separately for each chunk. Corners sit between voxels so they go from 0
+//up to the chunk size, one more than LocalCoord's 5 bit axes can hold. s is the side the
+//face is on, a its ambient occlusion level and i the voxel id used to look up the colour
+//palette. Colour variation is worked out per voxel in the fragment shader, so it doesn't
+//stop the greedy mesher from merging faces.
 #[repr(C)]
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
-    pub pos: [f32; 3],
-    pub color: [f32; 3],
+    data: u32,
 }
 
 impl Vertex {
+
+    //--------------------------------------------------
+
+    const POSITION_BITWISE: u32 = 63;
+    const Y_SHIFT: u32 = 6;
+    const Z_SHIFT: u32 = 12;
+    const SIDE_SHIFT: u32 = 18;
+    const AO_SHIFT: u32 = 21;
+    const VOXEL_SHIFT: u32 = 23;
+
+    //Largest corner position each axis can hold
+    pub const MAX_POSITION: u8 = 63;
+
+    //--------------------------------------------------
+
+    pub fn new(pos: [u8; 3], side: voxel::Side, ao: u8, voxel: voxel::VoxelId) -> Self {
+        let mut vertex = Self {
+            data: (side as u32) << Self::SIDE_SHIFT | (voxel.get() as u32) << Self::VOXEL_SHIFT,
+        };
+
+        vertex.set_pos(pos);
+        vertex.set_ao(ao);
+
+        vertex
+    }
+
+    pub fn get_pos(&self) -> [u8; 3] {
+        [
+            (self.data & Self::POSITION_BITWISE) as u8,
+            (self.data >> Self::Y_SHIFT & Self::POSITION_BITWISE) as u8,
+            (self.data >> Self::Z_SHIFT & Self::POSITION_BITWISE) as u8,
+        ]
+    }
+
+    pub fn set_pos(&mut self, pos: [u8; 3]) {
+        debug_assert!(pos.iter().all(|axis| *axis <= Self::MAX_POSITION), "Vertex position {:?} out of range", pos);
+
+        let position_bits = Self::POSITION_BITWISE | Self::POSITION_BITWISE << Self::Y_SHIFT | Self::POSITION_BITWISE << Self::Z_SHIFT;
+
+        self.data = self.data & !position_bits
+            | pos[0] as u32 & Self::POSITION_BITWISE
+            | (pos[1] as u32 & Self::POSITION_BITWISE) << Self::Y_SHIFT
+            | (pos[2] as u32 & Self::POSITION_BITWISE) << Self::Z_SHIFT;
+    }
+
+    //Goes from 0 (darkest) to 3 (unoccluded)
+    pub fn set_ao(&mut self, ao: u8) {
+        self.data = self.data & !(3 << Self::AO_SHIFT) | (ao.min(3) as u32) << Self::AO_SHIFT;
+    }
+
+    //--------------------------------------------------
+
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
             attributes: &[
-                //Pos
+                //Data
                 wgpu::VertexAttribute {
                     offset: 0,
                     shader_location: 0,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Uint32,
                 },
-                //Color
-                wgpu::VertexAttribute {
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x3,
-                }
             ],
         }   
     }
 }
 
 //====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vertex_fields_sit_where_the_shader_reads_them() {
+        let voxel = voxel::VoxelId::new(255).unwrap();
+        let mut vertex = Vertex::new([63, 0, 5], voxel::Side::Right, 2, voxel);
+
+        let expected = 63 | 5 << 12 | (voxel::Side::Right as u32) << 18 | 2 << 21 | 255 << 23;
+        assert_eq!(vertex.data, expected);
+        assert_eq!(vertex.data >> 31, 0);
+
+        //Changing one field leaves the others alone
+        vertex.set_pos([1, 62, 0]);
+        vertex.set_ao(7);
+        assert_eq!(vertex.get_pos(), [1, 62, 0]);
+        assert_eq!(vertex.data, 1 | 62 << 6 | (voxel::Side::Right as u32) << 18 | 3 << 21 | 255 << 23);
+    }
+}
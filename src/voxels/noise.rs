@@ -0,0 +1,70 @@
+//====================================================================
+
+//Small, dependency free noise functions. Everything here is a pure function of
+//its inputs so the same seed always gives the same world.
+
+//====================================================================
+
+pub fn hash(seed: u64, x: i32, y: i32, z: i32) -> u64 {
+    let mut h = seed
+        ^ (x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (z as u32 as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+
+    //splitmix64 finalizer
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+//Maps a hash onto 0..1
+pub fn hash_to_unit(hash: u64) -> f32 {
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+//====================================================================
+
+fn smooth_step(t: f32) -> f32 {
+    t * t * (3. - 2. * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+//Value noise on a 1x1 grid, returns 0..1
+pub fn value_noise_2d(seed: u64, x: f32, z: f32) -> f32 {
+    let (cell_x, cell_z) = (x.floor(), z.floor());
+    let (tx, tz) = (smooth_step(x - cell_x), smooth_step(z - cell_z));
+    let (cell_x, cell_z) = (cell_x as i32, cell_z as i32);
+
+    let corner = |dx: i32, dz: i32| hash_to_unit(hash(seed, cell_x + dx, 0, cell_z + dz));
+
+    let top = lerp(corner(0, 0), corner(1, 0), tx);
+    let bottom = lerp(corner(0, 1), corner(1, 1), tx);
+
+    lerp(top, bottom, tz)
+}
+
+//Layers several octaves of value noise, each at double the frequency and half the
+//strength of the last. Returns 0..1
+pub fn fractal_noise_2d(seed: u64, x: f32, z: f32, octaves: u32) -> f32 {
+    let mut total = 0.;
+    let mut max_value = 0.;
+
+    let mut frequency = 1.;
+    let mut amplitude = 1.;
+
+    for octave in 0..octaves {
+        let octave_seed = seed.wrapping_add(octave as u64);
+        total += value_noise_2d(octave_seed, x * frequency, z * frequency) * amplitude;
+        max_value += amplitude;
+
+        frequency *= 2.;
+        amplitude *= 0.5;
+    }
+
+    total / max_value
+}
+
+//====================================================================
@@ -0,0 +1,178 @@
+//====================================================================
+
+use super::{chunk, voxel};
+use crate::core::camera;
+
+//====================================================================
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub struct RaycastHit {
+    pub voxel_pos: glam::IVec3,
+    pub voxel: voxel::VoxelId,
+    //Face of the voxel the ray entered through
+    pub side: voxel::Side,
+    pub distance: f32,
+}
+
+//====================================================================
+
+pub fn raycast_from_camera(
+    chunks: &chunk::ChunkCollection,
+    camera: &camera::Camera,
+    max_distance: f32,
+) -> Option<RaycastHit> {
+    raycast(chunks, camera.get_position(), camera.get_direction(), max_distance)
+}
+
+//Walks the voxel grid along the ray one voxel at a time (Amanatides & Woo DDA) and
+//returns the first solid voxel. The voxel the ray starts in is skipped. Voxels in
+//chunks that aren't loaded count as empty and rays pass through non solid voxels.
+pub fn raycast(
+    chunks: &chunk::ChunkCollection,
+    origin: glam::Vec3,
+    direction: glam::Vec3,
+    max_distance: f32,
+) -> Option<RaycastHit> {
+    if direction == glam::Vec3::ZERO {
+        return None;
+    }
+
+    //Work in voxel space where voxel n spans n..n+1 on each axis. Distances along
+    //the ray stay in world units since the direction is scaled the same way.
+    let voxel_size = [voxel::VOXEL_WIDTH, voxel::VOXEL_HEIGHT, voxel::VOXEL_DEPTH];
+    let direction = direction.normalize().to_array();
+    let origin = origin.to_array();
+
+    let mut voxel_pos = [0i32; 3];
+    let mut step = [0i32; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+
+    for axis in 0..3 {
+        let start = origin[axis] / voxel_size[axis] + 0.5;
+        let dir = direction[axis] / voxel_size[axis];
+
+        voxel_pos[axis] = start.floor() as i32;
+
+        if dir > 0. {
+            step[axis] = 1;
+            t_max[axis] = (voxel_pos[axis] as f32 + 1. - start) / dir;
+            t_delta[axis] = 1. / dir;
+        } else if dir < 0. {
+            step[axis] = -1;
+            t_max[axis] = (start - voxel_pos[axis] as f32) / -dir;
+            t_delta[axis] = 1. / -dir;
+        }
+    }
+
+    loop {
+        let axis = if t_max[0] < t_max[1] {
+            if t_max[0] < t_max[2] { 0 } else { 2 }
+        } else if t_max[1] < t_max[2] {
+            1
+        } else {
+            2
+        };
+
+        let distance = t_max[axis];
+        if distance > max_distance {
+            return None;
+        }
+
+        voxel_pos[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+
+        let world_pos = glam::IVec3::from(voxel_pos);
+        let voxel = chunks.get_voxel(world_pos).filter(|id| chunks.get_registry().is_solid(Some(*id)));
+        if let Some(voxel) = voxel {
+            let side = match (axis, step[axis] > 0) {
+                (0, true) => voxel::Side::Left,
+                (0, false) => voxel::Side::Right,
+                (1, true) => voxel::Side::Bottom,
+                (1, false) => voxel::Side::Top,
+                (_, true) => voxel::Side::Back,
+                (_, false) => voxel::Side::Front,
+            };
+
+            return Some(RaycastHit {
+                voxel_pos: world_pos,
+                voxel,
+                side,
+                distance,
+            });
+        }
+    }
+}
+
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::{registry, terrain};
+    use std::sync::Arc;
+
+    //Empty chunks at the given positions with stone placed at each of the voxels
+    fn test_chunks(chunk_positions: &[glam::IVec3], stone: &[glam::IVec3]) -> chunk::ChunkCollection {
+        let registry = Arc::new(registry::VoxelRegistry::default());
+        let generator = Box::new(terrain::HeightmapGenerator::new(1, &registry));
+        let mut chunks = chunk::ChunkCollection::new(chunk::DEFAULT_CHUNK_SIZE, chunk::MeshingMode::Greedy, generator, registry.clone());
+
+        for chunk_pos in chunk_positions {
+            let voxels = Arc::new(chunk::ChunkVoxels::empty(chunk::DEFAULT_CHUNK_SIZE));
+            chunks.chunks.insert(*chunk_pos, chunk::Chunk::new(*chunk_pos, voxels, false));
+        }
+        for world_pos in stone {
+            assert!(chunks.set_voxel(*world_pos, registry.get_id("stone")));
+        }
+
+        chunks
+    }
+
+    //Centre of a voxel in world space
+    fn voxel_centre(world_pos: glam::IVec3) -> glam::Vec3 {
+        world_pos.as_vec3() * glam::Vec3::new(voxel::VOXEL_WIDTH, voxel::VOXEL_HEIGHT, voxel::VOXEL_DEPTH)
+    }
+
+    #[test]
+    fn ray_crosses_chunk_border_into_negative_coordinates() {
+        let target = glam::IVec3::new(-3, 2, 2);
+        let chunks = test_chunks(&[glam::IVec3::ZERO, glam::IVec3::new(-1, 0, 0)], &[target]);
+
+        let hit = raycast(&chunks, voxel_centre(glam::IVec3::new(2, 2, 2)), -glam::Vec3::X, 10.).unwrap();
+        assert_eq!(hit.voxel_pos, target);
+        assert_eq!(hit.side, voxel::Side::Right);
+        assert_eq!(hit.voxel_pos + hit.side.get_normal(), glam::IVec3::new(-2, 2, 2));
+        //From the centre of voxel 2 to the far face of voxel -2
+        assert!((hit.distance - 4.5 * voxel::VOXEL_WIDTH).abs() < 1e-4);
+    }
+
+    #[test]
+    fn ray_misses_beyond_max_distance() {
+        let origin = voxel_centre(glam::IVec3::new(2, 2, 2));
+        let chunks = test_chunks(&[glam::IVec3::ZERO, glam::IVec3::new(-1, 0, 0)], &[glam::IVec3::new(-3, 2, 2)]);
+
+        assert!(raycast(&chunks, origin, -glam::Vec3::X, 4. * voxel::VOXEL_WIDTH).is_none());
+        //Nothing solid that way, and the chunks beyond aren't loaded
+        assert!(raycast(&chunks, origin, glam::Vec3::X, 10.).is_none());
+        assert!(raycast(&chunks, origin, glam::Vec3::ZERO, 10.).is_none());
+    }
+
+    #[test]
+    fn hit_side_faces_back_along_the_ray() {
+        let origin = glam::IVec3::new(5, 5, 5);
+
+        for side in voxel::Side::ALL {
+            let target = origin - side.get_normal() * 3;
+            let chunks = test_chunks(&[glam::IVec3::ZERO], &[target]);
+
+            let direction = -side.get_normal().as_vec3();
+            let hit = raycast(&chunks, voxel_centre(origin), direction, 10.).unwrap();
+            assert_eq!(hit.voxel_pos, target);
+            assert_eq!(hit.side, side);
+            //The previous voxel along the ray, where a voxel would be placed
+            assert_eq!(hit.voxel_pos + hit.side.get_normal(), origin - side.get_normal() * 2);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//====================================================================
+
+use std::io::{Read, Write};
+
+use super::{
+    chunk::{ChunkSize, ChunkVoxels},
+    voxel,
+};
+
+//====================================================================
+
+//Region file layout (all values little endian):
+//
+//  Header
+//      magic           [u8; 4]     "CWRG"
+//      version         u16
+//      region size     u8          chunks per axis
+//      chunk size      [u8; 3]     voxels per axis (width, height, depth)
+//      offset table    [(u32, u32); REGION_SIZE^3]
+//                                  (offset, length) of each chunk, length 0 if missing
+//  Chunks
+//      palette length  u8                          minus one, so 256 entries fit
+//      palette         [u8; palette length]        voxel ids
+//      bits per index  u8                          0 if the palette has one entry
+//      indices         packed bytes, LSB first, x then y then z
+
+pub const REGION_MAGIC: [u8; 4] = *b"CWRG";
+pub const REGION_VERSION: u16 = 1;
+pub const REGION_SIZE: i32 = 8;
+
+const REGION_CHUNK_COUNT: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const HEADER_SIZE: usize = 4 + 2 + 1 + 3 + REGION_CHUNK_COUNT * 8;
+
+//====================================================================
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn chunk_to_region(chunk_pos: glam::IVec3) -> glam::IVec3 {
+    glam::IVec3::new(
+        chunk_pos.x.div_euclid(REGION_SIZE),
+        chunk_pos.y.div_euclid(REGION_SIZE),
+        chunk_pos.z.div_euclid(REGION_SIZE),
+    )
+}
+
+fn chunk_region_index(chunk_pos: glam::IVec3) -> usize {
+    let x = chunk_pos.x.rem_euclid(REGION_SIZE);
+    let y = chunk_pos.y.rem_euclid(REGION_SIZE);
+    let z = chunk_pos.z.rem_euclid(REGION_SIZE);
+
+    ((x * REGION_SIZE + y) * REGION_SIZE + z) as usize
+}
+
+pub fn region_file_name(region_pos: glam::IVec3) -> String {
+    format!("region_{}_{}_{}.cwr", region_pos.x, region_pos.y, region_pos.z)
+}
+
+fn parse_region_file_name(name: &str) -> Option<glam::IVec3> {
+    let coords = name.strip_prefix("region_")?.strip_suffix(".cwr")?;
+    let mut coords = coords.split('_').map(|value| value.parse::<i32>().ok());
+
+    let region_pos = glam::IVec3::new(coords.next()??, coords.next()??, coords.next()??);
+    match coords.next() {
+        Some(_) => None,
+        None => Some(region_pos),
+    }
+}
+
+//====================================================================
+
+fn encode_chunk(voxels: &ChunkVoxels) -> Vec<u8> {
+    let mut palette: Vec<u8> = Vec::new();
+    let chunk_size = voxels.get_size();
+    let mut indices = Vec::with_capacity(chunk_size.volume());
+
+    for coord in chunk_size.coords() {
+        let id = voxels.get(coord).map_or(0, voxel::VoxelId::get);
+
+        let index = match palette.iter().position(|entry| *entry == id) {
+            Some(index) => index,
+            None => {
+                palette.push(id);
+                palette.len() - 1
+            }
+        };
+        indices.push(index as u32);
+    }
+
+    let bits_per_index = bits_to_index(palette.len());
+
+    let mut data = Vec::new();
+    data.push((palette.len() - 1) as u8);
+    data.extend_from_slice(&palette);
+    data.push(bits_per_index);
+
+    if bits_per_index > 0 {
+        let mut packed = vec![0u8; (indices.len() * bits_per_index as usize).div_ceil(8)];
+        for (i, index) in indices.iter().enumerate() {
+            for bit in 0..bits_per_index as usize {
+                if index & (1 << bit) != 0 {
+                    let bit_pos = i * bits_per_index as usize + bit;
+                    packed[bit_pos / 8] |= 1 << (bit_pos % 8);
+                }
+            }
+        }
+        data.extend_from_slice(&packed);
+    }
+
+    data
+}
+
+fn decode_chunk(data: &[u8], chunk_size: ChunkSize) -> std::io::Result<ChunkVoxels> {
+    let palette_len = *data.first().ok_or_else(|| invalid_data("Chunk data is empty"))? as usize + 1;
+
+    let palette = data.get(1..1 + palette_len).ok_or_else(|| invalid_data("Chunk palette is truncated"))?;
+    //Ids missing from the registry are kept as they are and treated as empty space
+    let palette: Vec<Option<voxel::VoxelId>> = palette.iter().map(|id| voxel::VoxelId::new(*id)).collect();
+
+    let bits_per_index = *data.get(1 + palette_len).ok_or_else(|| invalid_data("Chunk data is truncated"))? as usize;
+    if bits_per_index != bits_to_index(palette.len()) as usize {
+        return Err(invalid_data("Chunk index size doesn't match its palette"));
+    }
+    let packed = &data[2 + palette_len..];
+
+    let mut voxels = ChunkVoxels::empty(chunk_size);
+
+    for (i, coord) in chunk_size.coords().enumerate() {
+        let mut index = 0;
+        for bit in 0..bits_per_index {
+            let bit_pos = i * bits_per_index + bit;
+            let byte = packed.get(bit_pos / 8).ok_or_else(|| invalid_data("Chunk indices are truncated"))?;
+            if byte & (1 << (bit_pos % 8)) != 0 {
+                index |= 1 << bit;
+            }
+        }
+
+        let voxel = palette.get(index).ok_or_else(|| invalid_data("Chunk index is outside its palette"))?;
+        voxels.set(coord, *voxel);
+    }
+
+    Ok(voxels)
+}
+
+//Region and chunk sizes as stored in the header
+fn header_sizes(chunk_size: ChunkSize) -> [u8; 4] {
+    let [width, height, depth] = chunk_size.to_array();
+    [REGION_SIZE as u8, width as u8, height as u8, depth as u8]
+}
+
+//Exact number of bits needed to index the palette, unlike ChunkVoxels which rounds up
+//to a width that divides a u64
+fn bits_to_index(palette_len: usize) -> u8 {
+    if palette_len <= 1 {
+        return 0;
+    }
+    (usize::BITS - (palette_len - 1).leading_zeros()) as u8
+}
+
+//====================================================================
+
+//Written to a temporary file first and then moved over the old one, so a crash part
+//way through can't leave a truncated region behind
+pub fn write_region(
+    path: &std::path::Path,
+    chunk_size: ChunkSize,
+    chunks: &[(glam::IVec3, &ChunkVoxels)],
+) -> std::io::Result<()> {
+    let mut offsets = vec![(0u32, 0u32); REGION_CHUNK_COUNT];
+    let mut body = Vec::new();
+
+    for (chunk_pos, voxels) in chunks {
+        let data = encode_chunk(voxels);
+        offsets[chunk_region_index(*chunk_pos)] = ((HEADER_SIZE + body.len()) as u32, data.len() as u32);
+        body.extend_from_slice(&data);
+    }
+
+    let temp_path = path.with_extension("cwr.tmp");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&temp_path)?);
+
+    file.write_all(&REGION_MAGIC)?;
+    file.write_all(&REGION_VERSION.to_le_bytes())?;
+    file.write_all(&header_sizes(chunk_size))?;
+
+    for (offset, length) in offsets {
+        file.write_all(&offset.to_le_bytes())?;
+        file.write_all(&length.to_le_bytes())?;
+    }
+
+    file.write_all(&body)?;
+    file.into_inner()?.sync_all()?;
+
+    std::fs::rename(&temp_path, path)
+}
+
+pub fn read_region(
+    path: &std::path::Path,
+    region_pos: glam::IVec3,
+    chunk_size: ChunkSize,
+) -> std::io::Result<Vec<(glam::IVec3, ChunkVoxels)>> {
+    let mut data = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() < HEADER_SIZE {
+        return Err(invalid_data("Region file is smaller than its header"));
+    }
+    if data[0..4] != REGION_MAGIC {
+        return Err(invalid_data("Not a region file"));
+    }
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != REGION_VERSION {
+        return Err(invalid_data("Unsupported region file version"));
+    }
+    if data[6..10] != header_sizes(chunk_size) {
+        return Err(invalid_data("Region file was saved with different region or chunk sizes"));
+    }
+
+    let region_origin = region_pos * REGION_SIZE;
+    let mut chunks = Vec::new();
+
+    for x in 0..REGION_SIZE {
+        for y in 0..REGION_SIZE {
+            for z in 0..REGION_SIZE {
+                let chunk_pos = region_origin + glam::IVec3::new(x, y, z);
+
+                let entry = 10 + chunk_region_index(chunk_pos) * 8;
+                let offset = u32::from_le_bytes(data[entry..entry + 4].try_into().unwrap()) as usize;
+                let length = u32::from_le_bytes(data[entry + 4..entry + 8].try_into().unwrap()) as usize;
+
+                if length == 0 {
+                    continue;
+                }
+
+                let chunk_data = data
+                    .get(offset..offset + length)
+                    .ok_or_else(|| invalid_data("Chunk offset is outside the region file"))?;
+
+                chunks.push((chunk_pos, decode_chunk(chunk_data, chunk_size)?));
+            }
+        }
+    }
+
+    Ok(chunks)
+}
+
+//Reads every region file in the directory. Files with other names are skipped.
+pub fn read_regions_in_dir(
+    dir: &std::path::Path,
+    chunk_size: ChunkSize,
+) -> std::io::Result<Vec<(glam::IVec3, ChunkVoxels)>> {
+    let mut chunks = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let region_pos = match path.file_name().and_then(|name| name.to_str()).and_then(parse_region_file_name) {
+            Some(region_pos) => region_pos,
+            None => continue,
+        };
+
+        chunks.extend(read_region(&path, region_pos, chunk_size)?);
+    }
+
+    Ok(chunks)
+}
+
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::chunk::DEFAULT_CHUNK_SIZE;
+
+    //Empty directory for a test to write region files to
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("cube_worlds_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn voxel_ids(voxels: &ChunkVoxels) -> Vec<Option<voxel::VoxelId>> {
+        voxels.get_size().coords().map(|coord| voxels.get(coord)).collect()
+    }
+
+    //Cycles through palette_len different voxels, empty space included
+    fn palette_voxels(palette_len: usize, seed: usize) -> ChunkVoxels {
+        let mut voxels = ChunkVoxels::empty(DEFAULT_CHUNK_SIZE);
+        for (i, coord) in DEFAULT_CHUNK_SIZE.coords().enumerate() {
+            voxels.set(coord, voxel::VoxelId::new(((i + seed) % palette_len) as u8));
+        }
+        voxels
+    }
+
+    fn round_trip(voxels: &ChunkVoxels) -> ChunkVoxels {
+        decode_chunk(&encode_chunk(voxels), voxels.get_size()).unwrap()
+    }
+
+    fn write_test_region(dir: &std::path::Path) -> std::path::PathBuf {
+        let voxels = palette_voxels(3, 0);
+        let path = dir.join(region_file_name(glam::IVec3::ZERO));
+        write_region(&path, DEFAULT_CHUNK_SIZE, &[(glam::IVec3::ONE, &voxels)]).unwrap();
+        path
+    }
+
+    fn assert_rejected(path: &std::path::Path, chunk_size: ChunkSize) {
+        match read_region(path, glam::IVec3::ZERO, chunk_size) {
+            Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Invalid region file was read"),
+        }
+    }
+
+    //--------------------------------------------------
+
+    #[test]
+    fn uniform_chunk_round_trips() {
+        let voxels = ChunkVoxels::filled(DEFAULT_CHUNK_SIZE, voxel::VoxelId::new(3));
+
+        //Just the palette, no indices
+        assert_eq!(encode_chunk(&voxels).len(), 3);
+        assert_eq!(voxel_ids(&round_trip(&voxels)), voxel_ids(&voxels));
+    }
+
+    #[test]
+    fn palettes_round_trip() {
+        for (palette_len, bits_per_index) in [(2, 1), (3, 2), (4, 2), (5, 3), (16, 4), (17, 5), (256, 8)] {
+            let voxels = palette_voxels(palette_len, 0);
+            let data = encode_chunk(&voxels);
+
+            assert_eq!(data[1 + palette_len], bits_per_index, "Palette of {}", palette_len);
+            assert_eq!(voxel_ids(&round_trip(&voxels)), voxel_ids(&voxels), "Palette of {}", palette_len);
+        }
+    }
+
+    #[test]
+    fn regions_round_trip_with_negative_coords() {
+        let dir = test_dir("negative_regions");
+
+        let chunk_positions = [
+            glam::IVec3::new(-1, -1, -1),
+            glam::IVec3::new(-8, -8, -8),
+            glam::IVec3::new(-9, 0, 7),
+            glam::IVec3::new(0, 0, 0),
+            glam::IVec3::new(8, -3, -17),
+        ];
+        let chunks: Vec<(glam::IVec3, ChunkVoxels)> = chunk_positions
+            .iter()
+            .enumerate()
+            .map(|(i, chunk_pos)| (*chunk_pos, palette_voxels(i + 2, i)))
+            .collect();
+
+        let mut regions: std::collections::HashMap<glam::IVec3, Vec<(glam::IVec3, &ChunkVoxels)>> =
+            std::collections::HashMap::new();
+        for (chunk_pos, voxels) in chunks.iter() {
+            regions.entry(chunk_to_region(*chunk_pos)).or_default().push((*chunk_pos, voxels));
+        }
+        assert_eq!(regions.len(), 4);
+
+        for (region_pos, region_chunks) in regions {
+            write_region(&dir.join(region_file_name(region_pos)), DEFAULT_CHUNK_SIZE, &region_chunks).unwrap();
+        }
+
+        let mut read_chunks = read_regions_in_dir(&dir, DEFAULT_CHUNK_SIZE).unwrap();
+        read_chunks.sort_by_key(|(chunk_pos, _)| chunk_positions.iter().position(|pos| pos == chunk_pos));
+
+        assert_eq!(read_chunks.len(), chunks.len());
+        for ((read_pos, read_voxels), (chunk_pos, voxels)) in read_chunks.iter().zip(chunks.iter()) {
+            assert_eq!(read_pos, chunk_pos);
+            assert_eq!(voxel_ids(read_voxels), voxel_ids(voxels), "Chunk {}", chunk_pos);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let dir = test_dir("bad_headers");
+        let path = write_test_region(&dir);
+        let data = std::fs::read(&path).unwrap();
+
+        assert!(read_region(&path, glam::IVec3::ZERO, DEFAULT_CHUNK_SIZE).is_ok());
+        assert_rejected(&path, ChunkSize::new(16, 16, 16).unwrap());
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        std::fs::write(&path, bad_magic).unwrap();
+        assert_rejected(&path, DEFAULT_CHUNK_SIZE);
+
+        let mut bad_version = data.clone();
+        bad_version[4..6].copy_from_slice(&(REGION_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, bad_version).unwrap();
+        assert_rejected(&path, DEFAULT_CHUNK_SIZE);
+
+        let mut bad_size = data.clone();
+        bad_size[7] += 1;
+        std::fs::write(&path, bad_size).unwrap();
+        assert_rejected(&path, DEFAULT_CHUNK_SIZE);
+
+        std::fs::write(&path, &data[..HEADER_SIZE - 1]).unwrap();
+        assert_rejected(&path, DEFAULT_CHUNK_SIZE);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
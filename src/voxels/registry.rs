@@ -0,0 +1,108 @@
+//====================================================================
+
+use super::voxel::{self, VoxelId};
+
+//====================================================================
+
+//Copy of res/voxels.toml built into the binary, used when the file can't be loaded
+const DEFAULT_CONFIG: &str = include_str!("../../res/voxels.toml");
+
+#[derive(serde::Deserialize)]
+struct VoxelConfig {
+    #[serde(default)]
+    voxel: Vec<voxel::Voxel>,
+}
+
+//====================================================================
+
+//Every voxel type, indexed by id. Ids that aren't defined are treated as empty space,
+//so chunks saved with a type that has since been removed still load.
+pub struct VoxelRegistry {
+    voxels: Vec<Option<voxel::Voxel>>,
+}
+
+impl VoxelRegistry {
+    pub fn load_from_file(path: &std::path::Path) -> std::io::Result<Self> {
+        Self::from_config(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_config(config: &str) -> std::io::Result<Self> {
+        let config: VoxelConfig = toml::from_str(config).map_err(|error| invalid_data(&error.to_string()))?;
+
+        let mut voxels = vec![None; u8::MAX as usize + 1];
+
+        for voxel in config.voxel {
+            if !(0. ..=1.).contains(&voxel.color_variance) {
+                return Err(invalid_data(&format!("Voxel '{}' has a colour variance outside of 0-1", voxel.name)));
+            }
+            if !(0. ..=1.).contains(&voxel.emissive) {
+                return Err(invalid_data(&format!("Voxel '{}' has an emissive level outside of 0-1", voxel.name)));
+            }
+            if !(0. ..=1.).contains(&voxel.alpha) {
+                return Err(invalid_data(&format!("Voxel '{}' has an alpha outside of 0-1", voxel.name)));
+            }
+            if voxels.iter().flatten().any(|other: &voxel::Voxel| other.name == voxel.name) {
+                return Err(invalid_data(&format!("Voxel name '{}' is used more than once", voxel.name)));
+            }
+
+            let slot = &mut voxels[voxel.id.get() as usize];
+            if slot.is_some() {
+                return Err(invalid_data(&format!("Voxel id {} is used more than once", voxel.id)));
+            }
+            *slot = Some(voxel);
+        }
+
+        Ok(Self { voxels })
+    }
+
+    //--------------------------------------------------
+
+    pub fn get(&self, id: VoxelId) -> Option<&voxel::Voxel> {
+        self.voxels[id.get() as usize].as_ref()
+    }
+
+    pub fn get_id(&self, name: &str) -> Option<VoxelId> {
+        self.iter().find(|voxel| voxel.name == name).map(|voxel| voxel.id)
+    }
+
+    //Voxel types in order of id
+    pub fn iter(&self) -> impl Iterator<Item = &voxel::Voxel> {
+        self.voxels.iter().flatten()
+    }
+
+    //Hides the faces of voxels behind it and shades their corners
+    pub fn is_opaque(&self, id: Option<VoxelId>) -> bool {
+        match id.and_then(|id| self.get(id)) {
+            Some(voxel) => !voxel.transparent && !voxel.is_translucent(),
+            None => false,
+        }
+    }
+
+    pub fn is_translucent(&self, id: Option<VoxelId>) -> bool {
+        match id.and_then(|id| self.get(id)) {
+            Some(voxel) => voxel.is_translucent(),
+            None => false,
+        }
+    }
+
+    pub fn is_solid(&self, id: Option<VoxelId>) -> bool {
+        match id.and_then(|id| self.get(id)) {
+            Some(voxel) => voxel.solid,
+            None => false,
+        }
+    }
+}
+
+impl Default for VoxelRegistry {
+    fn default() -> Self {
+        Self::from_config(DEFAULT_CONFIG).expect("Built in voxel config is invalid")
+    }
+}
+
+//====================================================================
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+//====================================================================
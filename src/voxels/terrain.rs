@@ -0,0 +1,114 @@
+//====================================================================
+
+use super::{
+    chunk::{self, ChunkVoxels},
+    noise, registry, voxel,
+};
+
+//====================================================================
+
+pub trait TerrainGenerator: Send + Sync {
+    fn generate_chunk(&self, chunk_pos: glam::IVec3, chunk_size: chunk::ChunkSize) -> ChunkVoxels;
+}
+
+//====================================================================
+
+//Rolling hills from a seeded noise heightmap. The top voxel of each column is the surface
+//voxel (grass), everything underneath is the fill voxel (stone) and everything above is air.
+pub struct HeightmapGenerator {
+    pub seed: u64,
+    //None leaves that layer empty
+    pub surface_voxel: Option<voxel::VoxelId>,
+    pub fill_voxel: Option<voxel::VoxelId>,
+
+    pub base_height: i32,
+    pub amplitude: f32,
+    pub scale: f32,
+    pub octaves: u32,
+}
+
+impl HeightmapGenerator {
+    pub fn new(seed: u64, registry: &registry::VoxelRegistry) -> Self {
+        Self {
+            seed,
+            surface_voxel: registry.get_id("grass"),
+            fill_voxel: registry.get_id("stone"),
+
+            base_height: 12,
+            amplitude: 10.,
+            scale: 0.03,
+            octaves: 4,
+        }
+    }
+
+    pub fn get_height(&self, world_x: i32, world_z: i32) -> i32 {
+        let noise = noise::fractal_noise_2d(
+            self.seed,
+            world_x as f32 * self.scale,
+            world_z as f32 * self.scale,
+            self.octaves,
+        );
+
+        self.base_height + ((noise * 2. - 1.) * self.amplitude).floor() as i32
+    }
+}
+
+impl TerrainGenerator for HeightmapGenerator {
+    fn generate_chunk(&self, chunk_pos: glam::IVec3, chunk_size: chunk::ChunkSize) -> ChunkVoxels {
+        let mut voxels = ChunkVoxels::empty(chunk_size);
+
+        let chunk_origin = chunk_pos * chunk_size.as_ivec3();
+
+        //Height of each column, indexed by x then z
+        let heights: Vec<i32> = (0..chunk_size.width() as i32)
+            .flat_map(|x| (0..chunk_size.depth() as i32).map(move |z| (x, z)))
+            .map(|(x, z)| self.get_height(chunk_origin.x + x, chunk_origin.z + z))
+            .collect();
+
+        for coord in chunk_size.coords() {
+            let height = heights[coord.x() as usize * chunk_size.depth() + coord.z() as usize];
+            let world_y = chunk_origin.y + coord.y() as i32;
+
+            let voxel = match world_y.cmp(&height) {
+                std::cmp::Ordering::Less => self.fill_voxel,
+                std::cmp::Ordering::Equal => self.surface_voxel,
+                std::cmp::Ordering::Greater => None,
+            };
+
+            voxels.set(coord, voxel);
+        }
+
+        voxels
+    }
+}
+
+//====================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Every voxel of the chunks around the surface, in order
+    fn generate(seed: u64) -> Vec<Option<voxel::VoxelId>> {
+        let generator = HeightmapGenerator::new(seed, &registry::VoxelRegistry::default());
+        let chunk_size = chunk::DEFAULT_CHUNK_SIZE;
+
+        [glam::IVec3::new(0, 1, 0), glam::IVec3::new(-3, 0, 2), glam::IVec3::new(4, 1, -5)]
+            .into_iter()
+            .flat_map(|chunk_pos| {
+                let voxels = generator.generate_chunk(chunk_pos, chunk_size);
+                chunk_size.coords().map(move |coord| voxels.get(coord))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_generates_same_terrain() {
+        assert_eq!(generate(1234), generate(1234));
+    }
+
+    #[test]
+    fn different_seed_generates_different_terrain() {
+        assert_ne!(generate(1234), generate(4321));
+    }
+}
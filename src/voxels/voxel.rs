@@ -18,6 +18,7 @@ pub const HALF_VOXEL_SIZE: glam::Vec3 = glam::Vec3::new(
 
 //====================================================================
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Side {
     Top,
     Bottom,
@@ -27,231 +28,325 @@ pub enum Side {
     Right
 }
 
+impl Side {
+    pub const ALL: [Side; 6] = [
+        Side::Top,
+        Side::Bottom,
+        Side::Front,
+        Side::Back,
+        Side::Left,
+        Side::Right,
+    ];
+
+    pub fn get_normal(&self) -> glam::IVec3 {
+        match self {
+            Side::Top => glam::IVec3::Y,
+            Side::Bottom => -glam::IVec3::Y,
+            Side::Front => glam::IVec3::Z,
+            Side::Back => -glam::IVec3::Z,
+            Side::Left => -glam::IVec3::X,
+            Side::Right => glam::IVec3::X,
+        }
+    }
+}
+
 //====================================================================
 
-#[derive(Clone, Copy)]
-pub enum Voxel {
-    Air,
-    Grass,
-    Stone,
+//Id of a voxel type in the VoxelRegistry. Chunks store these with None as empty space,
+//which still only takes a single byte per voxel.
+pub type VoxelId = std::num::NonZeroU8;
+
+//A type of voxel, loaded from the voxel config by the VoxelRegistry
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Voxel {
+    pub id: VoxelId,
+    pub name: String,
+    pub color: [f32; 3],
+    #[serde(default)]
+    pub color_variance: f32,
+    #[serde(default = "Voxel::default_solid")]
+    pub solid: bool,
+    #[serde(default)]
+    pub transparent: bool,
+    #[serde(default)]
+    pub emissive: f32,
+    #[serde(default = "Voxel::default_alpha")]
+    pub alpha: f32,
 }
 
 impl Voxel {
 
-    pub fn get_color(&self) -> [f32; 3] {
+    fn default_solid() -> bool {
+        true
+    }
 
-        let mut rng = rand::thread_rng();
+    fn default_alpha() -> f32 {
+        1.
+    }
 
-        match self {
-            Voxel::Air => [1., 1., 1.,],
-            Voxel::Grass => {
+    //Drawn blended over whatever is behind it, in the translucent pass
+    pub fn is_translucent(&self) -> bool {
+        self.alpha < 1.
+    }
+
+    //Corners of a voxel face, wound counter clockwise when looking at the face
+    pub fn get_side_corners(dir: Side) -> [[u8; 3]; 4] {
+        match dir {
+            Side::Top => [Self::V_0, Self::V_4, Self::V_7, Self::V_3],
+            Side::Bottom => [Self::V_2, Self::V_6, Self::V_5, Self::V_1],
+
+            Side::Front => [Self::V_4, Self::V_5, Self::V_6, Self::V_7],
+            Side::Back => [Self::V_3, Self::V_2, Self::V_1, Self::V_0],
 
-                [0., rand::Rng::gen_range(&mut rng, 0.3..1f32) , 0.,]
-            },
-            Voxel::Stone => [0.3, 0.3, 0.3,],
+            Side::Left => [Self::V_0, Self::V_1, Self::V_5, Self::V_4],
+            Side::Right => [Self::V_7, Self::V_6, Self::V_2, Self::V_3],
         }
     }
 
-    pub fn get_side(dir: Side, color: [f32; 3]) -> model::Mesh {
-        match dir {
-            Side::Top => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_0, color },
-                        model::Vertex {pos: Self::V_4, color },
-                        model::Vertex {pos: Self::V_7, color },
-                        model::Vertex {pos: Self::V_3, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
-            Side::Bottom => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_2, color },
-                        model::Vertex {pos: Self::V_6, color },
-                        model::Vertex {pos: Self::V_5, color },
-                        model::Vertex {pos: Self::V_1, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
-
-            Side::Front => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_4, color },
-                        model::Vertex {pos: Self::V_5, color },
-                        model::Vertex {pos: Self::V_6, color },
-                        model::Vertex {pos: Self::V_7, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
-            Side::Back => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_3, color },
-                        model::Vertex {pos: Self::V_2, color },
-                        model::Vertex {pos: Self::V_1, color },
-                        model::Vertex {pos: Self::V_0, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
-
-            Side::Left => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_0, color },
-                        model::Vertex {pos: Self::V_1, color },
-                        model::Vertex {pos: Self::V_5, color },
-                        model::Vertex {pos: Self::V_4, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
-            Side::Right => model::Mesh {
-                vertices: Vec::from([
-                        model::Vertex {pos: Self::V_7, color },
-                        model::Vertex {pos: Self::V_6, color },
-                        model::Vertex {pos: Self::V_2, color },
-                        model::Vertex {pos: Self::V_3, color },
-                    ]),
-                indices: Vec::from(Self::DEFAULT_INDICES),
-            },
+    //Face of a voxel at the origin, unoccluded until ambient occlusion is applied
+    pub fn get_side(dir: Side, voxel: VoxelId) -> model::Mesh {
+        model::Mesh {
+            vertices: Self::get_side_corners(dir)
+                .iter()
+                .map(|pos| model::Vertex::new(*pos, dir, 3, voxel))
+                .collect(),
+            indices: Vec::from(Self::DEFAULT_INDICES),
         }
     }
 
-    pub const V_0: [f32; 3] = [-HALF_VOXEL_SIZE.x,  HALF_VOXEL_SIZE.y, -HALF_VOXEL_SIZE.z]; //0
-    pub const V_1: [f32; 3] = [-HALF_VOXEL_SIZE.x, -HALF_VOXEL_SIZE.y, -HALF_VOXEL_SIZE.z]; //1
-    pub const V_2: [f32; 3] = [ HALF_VOXEL_SIZE.x, -HALF_VOXEL_SIZE.y, -HALF_VOXEL_SIZE.z]; //2
-    pub const V_3: [f32; 3] = [ HALF_VOXEL_SIZE.x,  HALF_VOXEL_SIZE.y, -HALF_VOXEL_SIZE.z]; //3
-    pub const V_4: [f32; 3] = [-HALF_VOXEL_SIZE.x,  HALF_VOXEL_SIZE.y,  HALF_VOXEL_SIZE.z]; //4
-    pub const V_5: [f32; 3] = [-HALF_VOXEL_SIZE.x, -HALF_VOXEL_SIZE.y,  HALF_VOXEL_SIZE.z]; //5
-    pub const V_6: [f32; 3] = [ HALF_VOXEL_SIZE.x, -HALF_VOXEL_SIZE.y,  HALF_VOXEL_SIZE.z]; //6
-    pub const V_7: [f32; 3] = [ HALF_VOXEL_SIZE.x,  HALF_VOXEL_SIZE.y,  HALF_VOXEL_SIZE.z]; //7
+    //Corners of a voxel in voxels from its lowest corner
+    pub const V_0: [u8; 3] = [0, 1, 0]; //0
+    pub const V_1: [u8; 3] = [0, 0, 0]; //1
+    pub const V_2: [u8; 3] = [1, 0, 0]; //2
+    pub const V_3: [u8; 3] = [1, 1, 0]; //3
+    pub const V_4: [u8; 3] = [0, 1, 1]; //4
+    pub const V_5: [u8; 3] = [0, 0, 1]; //5
+    pub const V_6: [u8; 3] = [1, 0, 1]; //6
+    pub const V_7: [u8; 3] = [1, 1, 1]; //7
 
     pub const DEFAULT_INDICES: [u16; 6] = [0, 1, 2, 0, 2, 3];
+    //Same quad split along the other diagonal
+    pub const FLIPPED_INDICES: [u16; 6] = [1, 2, 3, 1, 3, 0];
 
 }
 
 //====================================================================
 
-///
-/// 0 00000 00000 00000
-/// ?   x     y     z
-///   31744  922   31
-/// 
-/// each value can go from 0 -> 31
-#[derive(Default)]
+//Position of a voxel inside a chunk, packed into 5 bits per axis
+//
+//  0 00000 00000 00000
+//  ?   x     y     z
+//
+//Each axis goes from 0 to 31. Anything that would leave that range gives None instead of
+//spilling into the other axes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct LocalCoord (u16);
-#[allow(unused)]
 impl LocalCoord {
 
     //--------------------------------------------------
 
-    const X_BITWISE: u16 = 31744;
-    const Y_BITWISE: u16 = 992;
-    const Z_BITWISE: u16 = 31;
-
-    //--------------------------------------------------
+    const X_SHIFT: u16 = 10;
+    const Y_SHIFT: u16 = 5;
+    const AXIS_BITWISE: u16 = 31;
 
-    pub fn new(x: u8, y: u8, z: u8) -> Self {
+    //Largest value each axis can hold
+    pub const MAX_VALUE: u8 = 31;
 
-        let x = Self::clamp_value(x) << 10;
-        let y = Self::clamp_value(y) << 5;
-        let z = Self::clamp_value(z);
-
-        Self(x | y | z)
-    }
-
-    pub fn from_raw(raw: u16) -> Self {
-        Self(
-            raw & !32768
-        )
-    }
+    //--------------------------------------------------
 
-    pub fn to_raw(x: u8, y: u8, z: u8) -> u16 {
-        let x = Self::clamp_value(x) << 10;
-        let y = Self::clamp_value(y) << 5;
-        let z = Self::clamp_value(z);
+    pub fn new(x: u8, y: u8, z: u8) -> Option<Self> {
+        if x > Self::MAX_VALUE || y > Self::MAX_VALUE || z > Self::MAX_VALUE {
+            return None;
+        }
 
-        x + y + z
+        Some(Self((x as u16) << Self::X_SHIFT | (y as u16) << Self::Y_SHIFT | z as u16))
     }
 
-    pub fn get_raw(&self) -> u16 {
-        return self.0;
+    pub fn from_ivec3(pos: glam::IVec3) -> Option<Self> {
+        let axis = |value: i32| u8::try_from(value).ok();
+        Self::new(axis(pos.x)?, axis(pos.y)?, axis(pos.z)?)
     }
 
     //--------------------------------------------------
 
     pub fn x(&self) -> u8 {
-        return ((self.0 & Self::X_BITWISE) >> 10) as u8;
+        return ((self.0 >> Self::X_SHIFT) & Self::AXIS_BITWISE) as u8;
     }
     pub fn y(&self) -> u8 {
-        return ((self.0 & Self::Y_BITWISE) >> 5) as u8;
+        return ((self.0 >> Self::Y_SHIFT) & Self::AXIS_BITWISE) as u8;
     }
     pub fn z(&self) -> u8 {
-        return (self.0 & Self::Z_BITWISE) as u8;
+        return (self.0 & Self::AXIS_BITWISE) as u8;
     }
 
     pub fn xyz(&self) -> (u8, u8, u8) {
         return ( self.x(), self.y(), self.z() )
     }
 
+    pub fn as_ivec3(&self) -> glam::IVec3 {
+        return glam::IVec3::new(self.x() as i32, self.y() as i32, self.z() as i32);
+    }
+
     //--------------------------------------------------
 
-    pub fn set_x(&mut self, value: u8) {
+    pub fn with_x(&self, value: u8) -> Option<Self> {
+        Self::new(value, self.y(), self.z())
+    }
 
-        let value = Self::clamp_value(value);
-        let value = value << 10;
+    pub fn with_y(&self, value: u8) -> Option<Self> {
+        Self::new(self.x(), value, self.z())
+    }
 
-        self.0 &= !Self::X_BITWISE;
-        self.0 += value;
+    pub fn with_z(&self, value: u8) -> Option<Self> {
+        Self::new(self.x(), self.y(), value)
     }
 
-    pub fn set_y(&mut self, value: u8) {
-        let value = Self::clamp_value(value);
-        let value = value << 5;
+    //--------------------------------------------------
 
-        self.0 &= !Self::Y_BITWISE;
-        self.0 += value;
+    pub fn checked_add_x(&self, value: u8) -> Option<Self> {
+        self.with_x(self.x().checked_add(value)?)
     }
 
-    pub fn set_z(&mut self, value: u8) {
-        let value = Self::clamp_value(value);
+    pub fn checked_add_y(&self, value: u8) -> Option<Self> {
+        self.with_y(self.y().checked_add(value)?)
+    }
 
-        self.0 &= !Self::Z_BITWISE;
-        self.0 += value;
+    pub fn checked_add_z(&self, value: u8) -> Option<Self> {
+        self.with_z(self.z().checked_add(value)?)
     }
 
     //--------------------------------------------------
 
-    pub fn add_x(&mut self, value: u8) {
-        self.set_x(value + self.x());
+    pub fn checked_sub_x(&self, value: u8) -> Option<Self> {
+        self.with_x(self.x().checked_sub(value)?)
     }
-    
-    pub fn add_y(&mut self, value: u8) {
-        self.set_y(value + self.y());
+
+    pub fn checked_sub_y(&self, value: u8) -> Option<Self> {
+        self.with_y(self.y().checked_sub(value)?)
     }
 
-    pub fn add_z(&mut self, value: u8) {
-        self.set_z(value + self.z());
+    pub fn checked_sub_z(&self, value: u8) -> Option<Self> {
+        self.with_z(self.z().checked_sub(value)?)
     }
 
     //--------------------------------------------------
 
-    pub fn sub_x(&mut self, value: u8) {
-        self.set_x(self.x() - value);
+    //Moves by a signed amount on every axis at once
+    pub fn checked_offset(&self, offset: glam::IVec3) -> Option<Self> {
+        Self::from_ivec3(self.as_ivec3() + offset)
     }
-    
-    pub fn sub_y(&mut self, value: u8) {
-        self.set_y(self.y() - value);
+
+    //Neighbouring coord on a side, None when that would go past 0 or MAX_VALUE
+    pub fn checked_step(&self, side: Side) -> Option<Self> {
+        match side {
+            Side::Top => self.checked_add_y(1),
+            Side::Bottom => self.checked_sub_y(1),
+            Side::Front => self.checked_add_z(1),
+            Side::Back => self.checked_sub_z(1),
+            Side::Left => self.checked_sub_x(1),
+            Side::Right => self.checked_add_x(1),
+        }
     }
+}
+
+//====================================================================
 
-    pub fn sub_z(&mut self, value: u8) {
-        self.set_z(self.z() - value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(x: u8, y: u8, z: u8) -> LocalCoord {
+        LocalCoord::new(x, y, z).unwrap()
     }
 
-    //--------------------------------------------------
+    #[test]
+    fn new_checks_each_axis() {
+        assert_eq!(coord(0, 0, 0).xyz(), (0, 0, 0));
+        assert_eq!(coord(31, 31, 31).xyz(), (31, 31, 31));
+        assert_eq!(coord(1, 2, 3).xyz(), (1, 2, 3));
+
+        assert!(LocalCoord::new(32, 0, 0).is_none());
+        assert!(LocalCoord::new(0, 32, 0).is_none());
+        assert!(LocalCoord::new(0, 0, 32).is_none());
+        assert!(LocalCoord::new(255, 255, 255).is_none());
+    }
+
+    #[test]
+    fn from_ivec3_rejects_out_of_range() {
+        assert_eq!(LocalCoord::from_ivec3(glam::IVec3::new(4, 31, 0)).map(|coord| coord.xyz()), Some((4, 31, 0)));
+
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(-1, 0, 0)).is_none());
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(0, -1, 0)).is_none());
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(0, 0, -1)).is_none());
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(-256, 0, 0)).is_none());
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(32, 0, 0)).is_none());
+        assert!(LocalCoord::from_ivec3(glam::IVec3::new(0, 0, 256)).is_none());
+    }
+
+    #[test]
+    fn checked_add_overflows_past_31() {
+        let low = coord(0, 0, 0);
+        let high = coord(31, 31, 31);
+
+        assert_eq!(low.checked_add_x(31).map(|coord| coord.xyz()), Some((31, 0, 0)));
+        assert_eq!(low.checked_add_y(31).map(|coord| coord.xyz()), Some((0, 31, 0)));
+        assert_eq!(low.checked_add_z(31).map(|coord| coord.xyz()), Some((0, 0, 31)));
+
+        assert!(low.checked_add_x(32).is_none());
+        assert!(high.checked_add_x(1).is_none());
+        assert!(high.checked_add_y(1).is_none());
+        assert!(high.checked_add_z(1).is_none());
+        assert!(high.checked_add_x(255).is_none());
 
-    fn clamp_value(value: u8) -> u16 {
-        if value > 31 {
-            println!("User Error: value submitted to LocalVoxelCoord clamp_value() exceeds 31. Data will be lost");
+        assert_eq!(high.checked_add_x(0).map(|coord| coord.xyz()), Some((31, 31, 31)));
+    }
+
+    #[test]
+    fn checked_sub_underflows_past_0() {
+        let low = coord(0, 0, 0);
+        let high = coord(31, 31, 31);
+
+        assert_eq!(high.checked_sub_x(31).map(|coord| coord.xyz()), Some((0, 31, 31)));
+        assert_eq!(high.checked_sub_y(31).map(|coord| coord.xyz()), Some((31, 0, 31)));
+        assert_eq!(high.checked_sub_z(31).map(|coord| coord.xyz()), Some((31, 31, 0)));
+
+        assert!(high.checked_sub_x(32).is_none());
+        assert!(low.checked_sub_x(1).is_none());
+        assert!(low.checked_sub_y(1).is_none());
+        assert!(low.checked_sub_z(1).is_none());
+
+        assert_eq!(low.checked_sub_z(0).map(|coord| coord.xyz()), Some((0, 0, 0)));
+    }
+
+    #[test]
+    fn checked_offset_stays_inside() {
+        let low = coord(0, 0, 0);
+        let high = coord(31, 31, 31);
+
+        assert_eq!(low.checked_offset(glam::IVec3::splat(31)).map(|coord| coord.xyz()), Some((31, 31, 31)));
+        assert_eq!(high.checked_offset(glam::IVec3::splat(-31)).map(|coord| coord.xyz()), Some((0, 0, 0)));
+        assert_eq!(high.checked_offset(glam::IVec3::new(-1, 0, -31)).map(|coord| coord.xyz()), Some((30, 31, 0)));
+
+        assert!(low.checked_offset(glam::IVec3::new(-1, 0, 0)).is_none());
+        assert!(low.checked_offset(glam::IVec3::new(1, 1, -1)).is_none());
+        assert!(high.checked_offset(glam::IVec3::new(0, 1, 0)).is_none());
+        assert!(low.checked_offset(glam::IVec3::splat(32)).is_none());
+    }
+
+    #[test]
+    fn checked_step_follows_side_normals() {
+        let middle = coord(10, 10, 10);
+
+        for side in Side::ALL {
+            let next = middle.checked_step(side).unwrap();
+            assert_eq!(next.as_ivec3(), middle.as_ivec3() + side.get_normal(), "{:?}", side);
         }
-        return value as u16 & 31;
+
+        assert!(coord(0, 0, 0).checked_step(Side::Left).is_none());
+        assert!(coord(0, 0, 0).checked_step(Side::Bottom).is_none());
+        assert!(coord(0, 0, 0).checked_step(Side::Back).is_none());
+        assert!(coord(31, 31, 31).checked_step(Side::Right).is_none());
+        assert!(coord(31, 31, 31).checked_step(Side::Top).is_none());
+        assert!(coord(31, 31, 31).checked_step(Side::Front).is_none());
     }
 }
-
-//====================================================================
\ No newline at end of file
@@ -0,0 +1,188 @@
+//====================================================================
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+};
+
+use super::{
+    chunk::{Chunk, ChunkMeshData, ChunkNeighbours, ChunkSize, ChunkVoxels, MeshingMode},
+    registry, terrain,
+};
+
+//====================================================================
+
+//Every job gets a ticket so the ChunkCollection can tell if a result is still wanted
+//once it comes back (the chunk may have been unloaded or re-requested meanwhile).
+pub type JobTicket = u64;
+
+pub enum ChunkJob {
+    Generate {
+        ticket: JobTicket,
+        chunk_pos: glam::IVec3,
+    },
+    Mesh {
+        ticket: JobTicket,
+        chunk_pos: glam::IVec3,
+        voxels: Arc<ChunkVoxels>,
+        neighbours: Box<ChunkNeighbours>,
+        meshing_mode: MeshingMode,
+        lod: u8,
+    },
+}
+
+pub enum ChunkJobResult {
+    Generated {
+        ticket: JobTicket,
+        chunk_pos: glam::IVec3,
+        voxels: Box<ChunkVoxels>,
+    },
+    Meshed {
+        ticket: JobTicket,
+        chunk_pos: glam::IVec3,
+        mesh_data: ChunkMeshData,
+    },
+}
+
+//====================================================================
+
+pub struct ChunkWorkers {
+    job_sender: Option<mpsc::Sender<ChunkJob>>,
+    result_receiver: mpsc::Receiver<ChunkJobResult>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    //Set when the pool is dropped, so jobs still queued are skipped instead of run
+    stopping: Arc<AtomicBool>,
+
+    next_ticket: JobTicket,
+}
+
+impl ChunkWorkers {
+    pub fn new(
+        chunk_size: ChunkSize,
+        generator: Arc<dyn terrain::TerrainGenerator>,
+        registry: Arc<registry::VoxelRegistry>,
+    ) -> Self {
+        //Leave a core free for the render thread
+        let thread_count = std::thread::available_parallelism()
+            .map(|count| count.get().saturating_sub(1).max(1))
+            .unwrap_or(2);
+
+        let (job_sender, job_receiver) = mpsc::channel::<ChunkJob>();
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let threads = (0..thread_count)
+            .map(|index| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let generator = generator.clone();
+                let registry = registry.clone();
+                let stopping = stopping.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("Chunk Worker {}", index))
+                    .spawn(move || Self::run_worker(job_receiver, result_sender, stopping, chunk_size, generator, registry))
+                    .expect("Failed to spawn chunk worker thread")
+            })
+            .collect();
+
+        Self {
+            job_sender: Some(job_sender),
+            result_receiver,
+            threads,
+            stopping,
+
+            next_ticket: 0,
+        }
+    }
+
+    fn run_worker(
+        job_receiver: Arc<Mutex<mpsc::Receiver<ChunkJob>>>,
+        result_sender: mpsc::Sender<ChunkJobResult>,
+        stopping: Arc<AtomicBool>,
+        chunk_size: ChunkSize,
+        generator: Arc<dyn terrain::TerrainGenerator>,
+        registry: Arc<registry::VoxelRegistry>,
+    ) {
+        loop {
+            //Lock is only held while waiting for the next job, not while running it
+            let job = match job_receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return, //Sender dropped, pool is shutting down
+            };
+
+            //The channel still hands out queued jobs after the sender is dropped
+            if stopping.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let result = match job {
+                ChunkJob::Generate { ticket, chunk_pos } => ChunkJobResult::Generated {
+                    ticket,
+                    chunk_pos,
+                    voxels: Box::new(generator.generate_chunk(chunk_pos, chunk_size)),
+                },
+
+                ChunkJob::Mesh { ticket, chunk_pos, voxels, neighbours, meshing_mode, lod } => {
+                    let mesh_data = Chunk::build_chunk_mesh(&voxels, &neighbours, meshing_mode, lod, &registry);
+
+                    ChunkJobResult::Meshed { ticket, chunk_pos, mesh_data }
+                }
+            };
+
+            if result_sender.send(result).is_err() {
+                return;
+            }
+        }
+    }
+
+    //--------------------------------------------------
+
+    fn send(&mut self, job: impl FnOnce(JobTicket) -> ChunkJob) -> JobTicket {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+
+        if let Some(sender) = &self.job_sender {
+            sender.send(job(ticket)).expect("Chunk worker threads have stopped");
+        }
+
+        ticket
+    }
+
+    pub fn generate(&mut self, chunk_pos: glam::IVec3) -> JobTicket {
+        self.send(|ticket| ChunkJob::Generate { ticket, chunk_pos })
+    }
+
+    pub fn mesh(
+        &mut self,
+        chunk_pos: glam::IVec3,
+        voxels: Arc<ChunkVoxels>,
+        neighbours: ChunkNeighbours,
+        meshing_mode: MeshingMode,
+        lod: u8,
+    ) -> JobTicket {
+        let neighbours = Box::new(neighbours);
+        self.send(|ticket| ChunkJob::Mesh { ticket, chunk_pos, voxels, neighbours, meshing_mode, lod })
+    }
+
+    pub fn try_iter(&self) -> mpsc::TryIter<'_, ChunkJobResult> {
+        self.result_receiver.try_iter()
+    }
+}
+
+impl Drop for ChunkWorkers {
+    fn drop(&mut self) {
+        //Workers finish the job they are running, skip whatever is still queued, then
+        //exit once the closed channel is empty
+        self.stopping.store(true, Ordering::Relaxed);
+        self.job_sender = None;
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+//====================================================================